@@ -0,0 +1,10 @@
+CREATE TABLE miner_ban (
+  id SERIAL PRIMARY KEY,
+  created TIMESTAMP NOT NULL DEFAULT NOW(),
+  ip VARCHAR(45),
+  address VARCHAR(100),
+  reason TEXT NOT NULL,
+  expires TIMESTAMP,
+  source INTEGER NOT NULL
+);
+CREATE INDEX ON miner_ban (expires);
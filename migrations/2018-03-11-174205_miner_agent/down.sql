@@ -0,0 +1,2 @@
+ALTER TABLE valid_share DROP COLUMN agent;
+ALTER TABLE valid_share DROP COLUMN rig_id;
@@ -0,0 +1,2 @@
+ALTER TABLE valid_share ADD COLUMN agent VARCHAR(255);
+ALTER TABLE valid_share ADD COLUMN rig_id VARCHAR(100);
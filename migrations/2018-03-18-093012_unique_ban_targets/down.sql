@@ -0,0 +1,2 @@
+DROP INDEX miner_ban_address;
+DROP INDEX miner_ban_ip;
@@ -0,0 +1,5 @@
+-- Bans are upserted by IP or address, so only the newest ban for each can stay.
+DELETE FROM miner_ban a USING miner_ban b WHERE a.ip = b.ip AND a.id < b.id;
+DELETE FROM miner_ban a USING miner_ban b WHERE a.address = b.address AND a.id < b.id;
+CREATE UNIQUE INDEX miner_ban_ip ON miner_ban (ip);
+CREATE UNIQUE INDEX miner_ban_address ON miner_ban (address);
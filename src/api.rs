@@ -1,37 +1,180 @@
 use app::App;
+use bans::BanTarget;
+use stratum::Stratum;
+use db::models::{AgentShares, BanSource};
+use miner::parse_agent;
+use openssl::memcmp;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 use rocket;
 use rocket::*;
+use rocket::config::{Config as RocketConfig, Environment};
 use rocket::http::*;
+use rocket::request::{self, FromRequest};
+use rocket::response::status;
 use rocket_contrib::Json;
 use serde_json::*;
 
+/// Request guard for the admin routes, which checks the X-Admin-Token header against the
+/// admin_token in the config.
+struct Admin;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Admin {
+  type Error = ();
+
+  fn from_request(request: &'a Request<'r>) -> request::Outcome<Admin, ()> {
+    let app = match request.guard::<State<Arc<App>>>() {
+      Outcome::Success(app) => app,
+      _ => return Outcome::Failure((Status::InternalServerError, ())),
+    };
+    match (&app.config.admin_token, request.headers().get_one("X-Admin-Token")) {
+      (&Some(ref expected), Some(token)) if token_matches(expected, token) => {
+        Outcome::Success(Admin)
+      },
+      _ => Outcome::Failure((Status::Unauthorized, ())),
+    }
+  }
+}
+
+/// Compares tokens in constant time, so that response timings don't give away how much of a guess
+/// was right.
+fn token_matches(expected: &str, token: &str) -> bool {
+  expected.len() == token.len() && memcmp::eq(expected.as_bytes(), token.as_bytes())
+}
+
+#[derive(Deserialize)]
+struct BanRequest {
+  /// Either an IP or a wallet address.
+  target: String,
+  reason: String,
+  /// Length of the ban in seconds, the ban is permanent if this is left out.
+  duration: Option<u64>,
+}
+
+/// Groups the last hour of shares by mining software and version, so that buggy miner builds stand
+/// out.
+fn software_breakdown(agent_shares: Vec<AgentShares>) -> Vec<Value> {
+  let mut software: BTreeMap<(String, Option<String>), (i64, i64)> = BTreeMap::new();
+  for shares in agent_shares {
+    let (name, version) = match shares.agent {
+      Some(ref agent) => {
+        let (name, version) = parse_agent(agent);
+        (name.to_owned(), version.map(|v| v.to_owned()))
+      },
+      None => ("unknown".to_owned(), None),
+    };
+    let totals = software.entry((name, version)).or_insert((0, 0));
+    totals.0 += shares.miners;
+    totals.1 += shares.shares;
+  }
+  software.into_iter().map(|((name, version), (miners, shares))| json!({
+    "software": name,
+    "version": version,
+    "miners": miners,
+    "hashrate": shares / 3600,
+  })).collect()
+}
+
 #[get("/poolstats")]
-fn poolstats(app: State<Arc<App>>) -> Json<Value> {
+fn poolstats(app: State<Arc<App>>, stratum: State<Arc<Stratum>>) -> Json<Value> {
   let hashrates = app.db.get_hashrates();
+  let ports: Vec<Value> = app.config.ports.iter().map(|port| json!({
+    "port": port.port,
+    "starting_difficulty": port.starting_difficulty,
+    "transport": port.transport,
+    "tls": port.tls,
+    "tls_fingerprint": stratum.tls_fingerprint(port.port),
+  })).collect();
   Json(json!({
     "total_fee": app.total_fee(),
+    "ports": ports,
     "blocks": app.db.all_blocks(),
     "hashrates": hashrates,
+    "software": software_breakdown(app.db.shares_by_agent()),
+    "template_rewards": stratum.template_rewards(),
+    "daemons": app.daemon.statuses(),
+    "daemon_state": stratum.daemon_state(),
   }))
 }
 
 #[get("/minerstats/<address>")]
-fn minerstats(app: State<Arc<App>>, address: &RawStr) -> Json<Value> {
+fn minerstats(app: State<Arc<App>>, stratum: State<Arc<Stratum>>, address: &RawStr) -> Json<Value> {
   let address = address.as_str();
   let hashrates = app.db.hashrates_by_address(&app.address_pattern, address);
   let transactions = app.db.transactions_by_address(address);
   Json(json!({
     "hashrates": hashrates,
     "transactions": transactions,
+    "workers": stratum.workers(address),
   }))
 }
 
-pub fn init(app: Arc<App>) {
+/// Called by the daemon's --block-notify hook, so that we fetch the new template straight away.
+/// Only local processes can call this, since anyone else could use it to hammer the daemon.
+#[post("/notify/block")]
+fn notify_block(app: State<Arc<App>>, remote: SocketAddr) -> status::Custom<Json<Value>> {
+  if !remote.ip().is_loopback() {
+    return status::Custom(Status::Forbidden, Json(json!({
+      "error": "Block notifications are only accepted from localhost",
+    })));
+  }
+  app.block_notifier.notify();
+  status::Custom(Status::Ok, Json(json!({
+    "status": "OK",
+  })))
+}
+
+#[get("/admin/bans")]
+fn list_bans(app: State<Arc<App>>, _admin: Admin) -> Json<Value> {
+  Json(json!({
+    "bans": app.bans.active(),
+  }))
+}
+
+#[post("/admin/bans", format = "application/json", data = "<ban>")]
+fn add_ban(app: State<Arc<App>>, _admin: Admin, ban: Json<BanRequest>) -> status::Custom<Json<Value>> {
+  let ban = ban.into_inner();
+  match BanTarget::parse(&ban.target, &app.address_pattern) {
+    Some(target) => {
+      let saved_ban = app.bans.ban(&app.db, &target, &ban.reason,
+                                   ban.duration.map(Duration::from_secs), BanSource::Manual);
+      status::Custom(Status::Ok, Json(json!({
+        "ban": saved_ban,
+      })))
+    },
+    None => status::Custom(Status::BadRequest, Json(json!({
+      "error": "Ban target must be an IP or a wallet address",
+    }))),
+  }
+}
+
+#[delete("/admin/bans/<target>")]
+fn lift_ban(app: State<Arc<App>>, _admin: Admin, target: &RawStr) -> status::Custom<Json<Value>> {
+  match BanTarget::parse(target.as_str(), &app.address_pattern) {
+    Some(target) => status::Custom(Status::Ok, Json(json!({
+      "lifted": app.bans.lift(&app.db, &target),
+    }))),
+    None => status::Custom(Status::BadRequest, Json(json!({
+      "error": "Ban target must be an IP or a wallet address",
+    }))),
+  }
+}
+
+pub fn init(app: Arc<App>, stratum: Arc<Stratum>) {
+  let environment = Environment::active().unwrap_or(Environment::Production);
+  let rocket_config = RocketConfig::build(environment)
+    .address(app.config.api.address.to_string())
+    .port(app.config.api.port)
+    .finalize()
+    .expect("Invalid api address in config.toml");
   thread::spawn(move || {
-    rocket::ignite()
+    rocket::custom(rocket_config, true)
       .manage(app)
-      .mount("/", routes![poolstats, minerstats]).launch();
+      .manage(stratum)
+      .mount("/", routes![poolstats, minerstats, notify_block, list_bans, add_ban,
+                          lift_ban]).launch();
   });
 }
@@ -2,26 +2,37 @@ use std::sync::{Arc};
 use config::*;
 use db::*;
 use daemon_client::*;
+use bans::BanList;
+use notify::BlockNotifier;
 use regex::Regex;
 
 pub struct App {
   pub config: Config,
   pub db: DbAccess,
+  pub bans: BanList,
   pub daemon: DaemonClient,
   pub address_pattern: Regex,
+  pub block_notifier: Arc<BlockNotifier>,
 }
 
 impl App {
   pub fn new(config: Config) -> App {
+    App::with_db(config, DbAccess::new())
+  }
+
+  pub fn with_db(config: Config, db: DbAccess) -> App {
     let config_ref = Arc::new(config.clone());
     let currency_prefix = config.pool_wallet.chars().next().unwrap();
+    let bans = BanList::load(&db);
     App {
       config,
-      db: DbAccess::new(),
+      db,
+      bans,
       daemon: DaemonClient::new(config_ref.clone()),
       address_pattern: Regex::new(&(
         currency_prefix.to_string() + "[a-zA-Z0-9][123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz]{93}"
-      )).unwrap()
+      )).unwrap(),
+      block_notifier: Arc::new(BlockNotifier::new()),
     }
   }
 
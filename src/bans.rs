@@ -0,0 +1,142 @@
+use std::net::IpAddr;
+use std::sync::RwLock;
+use std::time::Duration;
+use chrono::{Utc, NaiveDateTime};
+use chrono::Duration as ChronoDuration;
+use regex::Regex;
+use db::DbAccess;
+use db::models::*;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BanTarget {
+  Ip(IpAddr),
+  Address(String),
+}
+
+impl BanTarget {
+  /// Interprets the given string as either an IP or a wallet address, which is what the admin API
+  /// accepts when adding or lifting bans.
+  pub fn parse(target: &str, address_pattern: &Regex) -> Option<BanTarget> {
+    if let Ok(ip) = target.parse::<IpAddr>() {
+      Some(BanTarget::Ip(ip))
+    } else if address_pattern.is_match(target) {
+      Some(BanTarget::Address(target.to_owned()))
+    } else {
+      None
+    }
+  }
+
+  fn matches(&self, ban: &MinerBan) -> bool {
+    match self {
+      &BanTarget::Ip(ref ip) => ban.ip.as_ref().map_or(false, |banned| banned == &ip.to_string()),
+      &BanTarget::Address(ref address) => ban.address.as_ref().map_or(false, |banned| banned == address),
+    }
+  }
+}
+
+fn is_active(ban: &MinerBan, now: NaiveDateTime) -> bool {
+  ban.expires.map_or(true, |expires| expires > now)
+}
+
+/// Keeps the active bans in memory, so that checking a share submission against them doesn't need
+/// a database round trip.  The bans are shared across all stratum ports, and written through to the
+/// database so that they survive restarts.
+pub struct BanList {
+  bans: RwLock<Vec<MinerBan>>,
+}
+
+impl BanList {
+  pub fn load(db: &DbAccess) -> BanList {
+    let now = Utc::now().naive_utc();
+    db.delete_expired_bans(now);
+    BanList {
+      bans: RwLock::new(db.active_bans(now)),
+    }
+  }
+
+  pub fn find(&self, target: &BanTarget) -> Option<MinerBan> {
+    let now = Utc::now().naive_utc();
+    self.bans.read().unwrap().iter()
+      .find(|ban| is_active(ban, now) && target.matches(ban))
+      .map(|ban| ban.clone())
+  }
+
+  pub fn active(&self) -> Vec<MinerBan> {
+    let now = Utc::now().naive_utc();
+    self.bans.read().unwrap().iter()
+      .filter(|ban| is_active(ban, now))
+      .map(|ban| ban.clone())
+      .collect()
+  }
+
+  /// Bans the target for the given duration, or permanently if there is no duration.  This replaces
+  /// any earlier ban on the target.
+  pub fn ban(&self, db: &DbAccess, target: &BanTarget, reason: &str, duration: Option<Duration>,
+             source: BanSource) -> MinerBan {
+    let now = Utc::now().naive_utc();
+    let expires = duration.map(|duration| now + ChronoDuration::seconds(duration.as_secs() as i64));
+    let (ip, address) = match target {
+      &BanTarget::Ip(ref ip) => (Some(ip.to_string()), None),
+      &BanTarget::Address(ref address) => (None, Some(address.to_owned())),
+    };
+    let new_ban = NewMinerBan {
+      ip: ip.as_ref().map(|ip| ip.as_str()),
+      address: address.as_ref().map(|address| address.as_str()),
+      reason,
+      expires,
+      source: source.into(),
+    };
+    // If the database is unavailable, the ban still applies until the pool restarts, since the
+    // automatic bans are what protect the pool from floods of bad shares.
+    let saved_ban = db.add_ban(&new_ban).unwrap_or_else(|| MinerBan {
+      id: 0,
+      created: now,
+      ip: ip.to_owned(),
+      address: address.to_owned(),
+      reason: reason.to_owned(),
+      expires,
+      source: new_ban.source,
+    });
+    let mut bans = self.bans.write().unwrap();
+    bans.retain(|ban| !target.matches(ban));
+    bans.push(saved_ban.clone());
+    saved_ban
+  }
+
+  /// Lifts all bans on the target, returning the number of bans that were removed.
+  pub fn lift(&self, db: &DbAccess, target: &BanTarget) -> usize {
+    let lifted_in_db = db.lift_bans(target);
+    let mut bans = self.bans.write().unwrap();
+    let bans_before = bans.len();
+    bans.retain(|ban| !target.matches(ban));
+    ::std::cmp::max(lifted_in_db, bans_before - bans.len())
+  }
+
+  /// Forgets the bans that have expired, and deletes them from the database too.
+  pub fn prune_expired(&self, db: &DbAccess) {
+    let now = Utc::now().naive_utc();
+    let mut bans = self.bans.write().unwrap();
+    let bans_before = bans.len();
+    bans.retain(|ban| is_active(ban, now));
+    if bans.len() < bans_before {
+      db.delete_expired_bans(now);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use bans::*;
+
+  #[test]
+  fn test_ban_target_parsing() {
+    let address_pattern = Regex::new("W[a-zA-Z0-9]{10}").unwrap();
+    assert_eq!(BanTarget::parse("127.0.0.1", &address_pattern),
+               Some(BanTarget::Ip("127.0.0.1".parse().unwrap())));
+    assert_eq!(BanTarget::parse("::1", &address_pattern),
+               Some(BanTarget::Ip("::1".parse().unwrap())));
+    assert_eq!(BanTarget::parse("Wabcdefghij", &address_pattern),
+               Some(BanTarget::Address("Wabcdefghij".to_owned())));
+    assert_eq!(BanTarget::parse("not a target", &address_pattern), None);
+  }
+}
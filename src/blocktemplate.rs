@@ -1,19 +1,19 @@
-use crypto::longkeccak::keccak;
 use crypto::cryptonote_utils::*;
+use cryptonote::Block;
 use std::sync::atomic::*;
 use std::sync::*;
 use std::result::Result as StdResult;
-use std::cmp::min;
+use std::cmp::{min, max};
 use uuid::*;
 use jsonrpc_core::*;
 use mithril::byte_string;
-use mithril::cryptonight::hash;
 use concurrent_hashmap::*;
 use app::App;
-
-// The number of hex digits in a cryptonote block header - the part before the transactions and
-// signatures.  This is always a fixed length, though the rest of a block can vary.
-const BLOCK_HEADER_LENGTH: usize = 86;
+use config::Fork;
+use lru_time_cache::LruCache;
+use chrono::{Utc, NaiveDateTime};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
 #[derive(Debug)]
 pub struct SuccessfulBlock {
@@ -21,91 +21,158 @@ pub struct SuccessfulBlock {
   pub blob: String,
 }
 
+/// Bytes that we ask the daemon to reserve in the miner transaction's extra field.  Each job's
+/// extra nonce goes at the start, and proxies fill in the rest.
+pub const RESERVE_SIZE: usize = 16;
+
+/// Bytes of the reserved space that hold each job's extra nonce.
+const EXTRA_NONCE_SIZE: usize = 8;
+
+/// Why a share was turned down.  The messages are the ones that miners recognize from other
+/// cryptonote pools.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejection {
+  /// The hash doesn't meet the job's difficulty.
+  LowDifficulty,
+  Duplicate,
+  /// The job is gone, usually because the network moved on to a new block.
+  Expired,
+  /// The submission doesn't belong to a session that we know about.
+  Unauthenticated,
+}
+
+impl Rejection {
+  pub fn message(&self) -> &'static str {
+    match self {
+      &Rejection::LowDifficulty => "Low difficulty share",
+      &Rejection::Duplicate => "Duplicate share",
+      &Rejection::Expired => "Block expired",
+      &Rejection::Unauthenticated => "Unauthenticated",
+    }
+  }
+
+  /// Whether the miner's IP should be banned for the share.  Expired jobs and lost sessions happen
+  /// to honest miners, but the rest mean a broken or dishonest miner.
+  pub fn bans_miner(&self) -> bool {
+    match self {
+      &Rejection::Expired | &Rejection::Unauthenticated => false,
+      _ => true,
+    }
+  }
+}
+
 #[derive(Debug)]
 pub enum JobResult {
   BlockFound(SuccessfulBlock),
   SharesAccepted,
-  SharesRejected,
+  SharesRejected(Rejection),
 }
 
 pub struct Job {
   pub id: String,
   pub hash_type: HashType,
+  /// The block that this job builds on.
+  pub prev_hash: String,
+  /// The cryptonight variant for this job's block.
+  pub variant: u8,
   pub height: u64,
   pub difficulty: u64,
   pub diff_hex: String,
   pub hashing_blob: String,
-  pub template_blob: String,
+  /// The parsed block template, which every job made from it shares.
+  pub template: Arc<Block>,
+  /// The miner transaction's extra field, with this job's extra nonce filled in.  This is all of
+  /// the block that differs between jobs.
+  pub extra: Vec<u8>,
   pub extra_nonce: String,
-  pub reserved_offset: u32,
+  /// Where the extra nonce starts in the miner transaction's extra field.
+  pub extra_nonce_offset: usize,
+  /// The template's merkle branch for the miner transaction.
+  pub merkle_branch: Arc<Vec<Vec<u8>>>,
   pub network_difficulty: u64,
   submissions: ConcHashMap<String, bool>,
 }
 
 impl Job {
+  /// Checks a miner's share.  The nonce has to be 8 hex digits, which submit makes sure of before
+  /// it gets here.
+  ///
+  /// On NiceHash ports we don't check the top byte of the nonce, even though NiceHash reserves it.
+  /// NiceHash fills that byte in itself, differently for each of the rigs that share a connection
+  /// to us, so any value is one that an honest miner can send.  Duplicates are still caught, since
+  /// the byte is part of the nonce that they are keyed by.
   pub fn check_submission(&self, nonce: &String) -> JobResult {
-    if nonce.len() != 8 {
-      return JobResult::SharesRejected;
-    }
     let previous_submission = self.submissions.insert(nonce.to_owned(), true);
     if let Some(_) = previous_submission {
-      return JobResult::SharesRejected;
+      return JobResult::SharesRejected(Rejection::Duplicate);
     }
-    let blob = &self.hashing_blob;
+    self.check_hash(&self.extra, &self.hashing_blob, nonce)
+  }
+
+  /// Checks a share from a proxy, which fills in the reserved space after our extra nonce itself,
+  /// so that it can hand out many jobs from the one we gave it.  Like xmr-node-proxy, the pool nonce
+  /// tells its worker threads apart, and the worker nonce tells apart the miners of each thread.
+  pub fn check_proxy_submission(&self, pool_nonce: u32, worker_nonce: u32, nonce: &String)
+                                -> JobResult {
+    // The proxy writes both of its nonces as big-endian numbers.
+    let proxy_nonce = format!("{:08x}{:08x}", pool_nonce, worker_nonce);
+    let previous_submission = self.submissions.insert(format!("{}{}", proxy_nonce, nonce), true);
+    if let Some(_) = previous_submission {
+      return JobResult::SharesRejected(Rejection::Duplicate);
+    }
+    let extra_nonce = format!("{}{}", self.extra_nonce, proxy_nonce);
+    let extra = with_extra_nonce(&self.template, self.extra_nonce_offset, &extra_nonce);
+    let hashing_blob = bytes_to_hex(self.template.hashing_blob_with_branch(&extra,
+                                                                          &self.merkle_branch));
+    self.check_hash(&extra, &hashing_blob, nonce)
+  }
+
+  /// The block template, with our extra nonce filled in.  This is what proxies get instead of a
+  /// hashing blob.
+  pub fn template_blob_with_extra_nonce(&self) -> String {
+    bytes_to_hex(self.template.serialize_with_extra(&self.extra))
+  }
+
+  /// The algorithm that miners need to hash this job with.
+  pub fn algo(&self) -> &'static str {
+    self.hash_type.algo(self.variant)
+  }
+
+  /// Where the extra nonce starts in the serialized block, in bytes.
+  pub fn reserved_offset(&self) -> usize {
+    self.template.extra_offset() + self.extra_nonce_offset
+  }
+
+  fn check_hash(&self, extra: &[u8], blob: &str, nonce: &str) -> JobResult {
     // Here for the most part we work with hex strings - there's probably a small performance
     // penalty for doing so, but the vast majority of the time here is going to be spent computing
     // the cryptonight hash anyways.
 
-    // The miner's provided nonce forms the last 8 bytes of the block header.  The original block
+    // The miner's provided nonce forms the last 4 bytes of the block header.  The original block
     // hashing blob we sent to the miner has zeroes there, so we replace them with the nonce that
     // the miner found.
-    let (pre_nonce, _) = blob.split_at(BLOCK_HEADER_LENGTH - 8);
-    let (_, post_nonce) = blob.split_at(BLOCK_HEADER_LENGTH);
+    let nonce_start = self.template.header.nonce_offset() * 2;
+    let (pre_nonce, _) = blob.split_at(nonce_start);
+    let (_, post_nonce) = blob.split_at(nonce_start + 8);
     let hash_input = byte_string::string_to_u8_array(&format!("{}{}{}", pre_nonce, nonce, post_nonce));
-    let version = if pre_nonce.starts_with("0707") || true {
-      // TODO eventually need a more permanent approach to detecting versions, unfortunately there
-      // are some version mismatches in the headers (07 flags the first hard fork for aeon and
-      // monero, whereas 04 for turtlecoin).  Probably just want to have currency+version specified
-      // in the config.
-      hash::HashVersion::Version7
-    }
-    else {
-      hash::HashVersion::Version6
-    };
-    let hash = cn_hash(&hash_input, &self.hash_type, version);
+    let hash = cn_hash(&hash_input, &self.hash_type, hash_version(self.variant));
     let hash_val = byte_string::hex2_u64_le(&hash[48..]);
     let achieved_difficulty = u64::max_value() / hash_val;
     if achieved_difficulty >= self.difficulty {
       if achieved_difficulty >= self.network_difficulty {
-        // The construction of the block ID is similar to the proof-of-work hash, except that:
-        // - The hash input is prefixed with a length value before hashing.  It's not obvious why
-        //   this is necessary, but probably has something to do with the fact that keccak is the
-        //   first step in cryptonight, and that it wouldn't seem right to have the eventual block
-        //   ID be there at some point in the cryptonight state.
-        // - The fast hashing function, keccak, is used instead of cryptonight.
-        let mut input_with_length = to_varint(hash_input.len());
-        input_with_length.extend(&hash_input);
-        let block_id = bytes_to_hex(keccak(&input_with_length)[..32].to_vec());
+        // The block ID is built from the hashing blob much like the proof-of-work hash, except
+        // that the blob is prefixed with its length, and the fast hashing function, keccak, is used
+        // instead of cryptonight.  Blocks are rare enough that copying the whole template for one
+        // is fine.
+        let mut candidate = (*self.template).clone();
+        candidate.miner_tx.extra = extra.to_vec();
+        candidate.header.nonce = u32::from_str_radix(nonce, 16).unwrap_or(0).swap_bytes();
+        let block_id = bytes_to_hex(candidate.id().to_vec());
+        let block_candidate = bytes_to_hex(candidate.serialize());
         info!("Valid block candidate {}", &block_id);
-        let start_blob = &self.template_blob[..(BLOCK_HEADER_LENGTH - 8)];
-        // For some reason the reserved offset is 1-indexed, so we have to subtract 1 byte (2 hexes)
-        let extra_nonce_start = self.reserved_offset as usize * 2 - 2;
-        let middle_blob = &self.template_blob[BLOCK_HEADER_LENGTH..extra_nonce_start];
-        let extra_nonce_end = extra_nonce_start + 16;
-        let end_blob = &self.template_blob[(extra_nonce_end)..];
-        let block_candidate = format!(
-          "{}{}{}{}{}",
-          start_blob,
-          nonce,
-          middle_blob,
-          self.extra_nonce,
-          end_blob
-        );
         debug!("Block candidate for difficulty {}, achieved {}", self.network_difficulty,
-              achieved_difficulty);
-        debug!("Block template blob: {}", self.template_blob);
-        debug!("Formatted candidate: {} {} {} {} {}", start_blob, nonce, middle_blob,
-               self.extra_nonce, end_blob);
+               achieved_difficulty);
+        debug!("Formatted candidate: {}", block_candidate);
         return JobResult::BlockFound(SuccessfulBlock {
           id: block_id,
           blob: block_candidate,
@@ -115,12 +182,59 @@ impl Job {
     } else {
       warn!("Bad job submission");
     }
-    JobResult::SharesRejected
+    JobResult::SharesRejected(Rejection::LowDifficulty)
   }
 }
 
+/// Monero's seconds between blocks, for coins that don't configure a block_target_time.
+const BLOCK_TARGET_TIME: u64 = 120;
+
+/// How many block target times can go by without a new block before we suspect that the daemon is
+/// stuck, unless the config gives a max_template_age.  A healthy network only goes this long
+/// without a block about once in 20000 stretches.
+const MAX_TEMPLATE_AGE_BLOCKS: u64 = 10;
+
+/// How the daemon's health changed since the last staleness check.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Staleness {
+  Unchanged,
+  /// The daemon has just started looking stuck.
+  Stale,
+  /// The daemon was stuck and has just recovered, so miners need new jobs.
+  Recovered,
+}
+
+/// How many template changes the reward history keeps.
+const REWARD_HISTORY_LENGTH: usize = 100;
+
+/// Why we switched to a new template.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateChange {
+  NewBlock,
+  Reorganization,
+  MoreFees,
+}
+
+/// The reward of a template that we switched miners to, which shows how much the fee-based
+/// refreshes are bringing in.
+#[derive(Serialize, Clone)]
+pub struct TemplateReward {
+  pub time: NaiveDateTime,
+  pub height: u64,
+  pub reward: u64,
+  pub change: TemplateChange,
+}
+
 pub struct JobProvider {
   template: RwLock<BlockTemplate>,
+  reward_history: Mutex<VecDeque<TemplateReward>>,
+  /// When the chain's tip last moved, according to the templates we got.
+  last_new_block: Mutex<Instant>,
+  /// Why the daemon looks stuck, if it does.
+  stale_reason: RwLock<Option<String>>,
+  /// Blocks that templates used to build on, until a reorg took them out of the main chain.
+  orphaned_parents: Mutex<LruCache<String, ()>>,
   nonce: AtomicUsize,
   app: Arc<App>,
   hash_type: HashType,
@@ -135,63 +249,117 @@ impl JobProvider {
     };
     JobProvider {
       template: RwLock::new(Default::default()),
+      reward_history: Mutex::new(VecDeque::new()),
+      last_new_block: Mutex::new(Instant::now()),
+      stale_reason: RwLock::new(None),
+      orphaned_parents: Mutex::new(LruCache::with_capacity(16)),
       nonce: AtomicUsize::new(0),
       app,
       hash_type,
     }
   }
 
-  pub fn get_job(&self, difficulty: u64) -> Option<Job> {
-    // The job difficulty typically only exceeds the network difficulty shortly after firing
-    // up a testnet.  Aside from that, sending out jobs higher than the network difficulty would
-    // be unlikely, but undesirable, since it would mean telling miners not to send in completed
-    // blocks.
-    let job_id = &Uuid::new_v4().to_string();
-    let template_data = self.template.read().unwrap();
-    let capped_difficulty = min(difficulty, template_data.difficulty);
-    let target_hex = get_target_hex(capped_difficulty);
+  pub fn hash_type(&self) -> &HashType {
+    &self.hash_type
+  }
+
+  /// Why the daemon looks stuck, if it does.
+  pub fn stale_reason(&self) -> Option<String> {
+    self.stale_reason.read().unwrap().clone()
+  }
+
+  /// Whether miners aren't getting new jobs, since the daemon looks stuck and the config asks us to
+  /// hold off.  While frozen, logins succeed without a job, getjob fails, and the jobs that miners
+  /// already had are dropped, so that shares on the old tip are rejected as expired.  Miners get
+  /// new jobs as soon as the daemon recovers.
+  pub fn is_frozen(&self) -> bool {
+    self.app.config.freeze_jobs_when_stale && self.stale_reason.read().unwrap().is_some()
+  }
+
+  /// Checks whether the daemon has fallen behind the network, or has gone too long without a new
+  /// block.
+  pub fn check_staleness(&self) -> Staleness {
+    let block_target_time = self.app.config.block_target_time.unwrap_or(BLOCK_TARGET_TIME);
+    let max_template_age = Duration::from_secs(
+      self.app.config.max_template_age.unwrap_or(MAX_TEMPLATE_AGE_BLOCKS * block_target_time));
+    let template_age = self.last_new_block.lock().unwrap().elapsed();
+    let reason = self.app.daemon.sync_problem().or_else(|| if template_age > max_template_age {
+      Some(format!("No new block for {} seconds", template_age.as_secs()))
+    } else {
+      None
+    });
+    let mut stale_reason = self.stale_reason.write().unwrap();
+    let staleness = match (&*stale_reason, &reason) {
+      (&None, &Some(ref reason)) => {
+        if self.app.config.freeze_jobs_when_stale {
+          error!("Daemon looks stuck, so miners won't get new jobs: {}", reason);
+        } else {
+          error!("Daemon looks stuck: {}", reason);
+        }
+        Staleness::Stale
+      },
+      (&Some(_), &None) => {
+        info!("Daemon has caught up with the network again");
+        Staleness::Recovered
+      },
+      _ => Staleness::Unchanged,
+    };
+    *stale_reason = reason;
+    staleness
+  }
 
+  /// The cryptonight variant of the current template, which changes at hard forks.
+  pub fn variant(&self) -> u8 {
+    self.template.read().unwrap().variant
+  }
+
+  /// The rewards of the most recent templates, oldest first.
+  pub fn reward_history(&self) -> Vec<TemplateReward> {
+    self.reward_history.lock().unwrap().iter().cloned().collect()
+  }
+
+  /// Whether a reorg has left this job building on a block that's no longer in the main chain, so
+  /// that shares for it would be wasted.
+  pub fn is_orphaned(&self, job: &Job) -> bool {
+    self.orphaned_parents.lock().unwrap().get(&job.prev_hash).is_some()
+  }
+
+  pub fn get_job(&self, difficulty: u64) -> Option<Job> {
     // The extra_nonce field allows us to issue multiple jobs using the same block template, without
     // any of those jobs being identical.  If they were identical, a miner could request the same
     // job within multiple connections or difficulties, and submit duplicate "proof" of the same
     // work.
     let new_nonce = self.nonce.fetch_add(1, Ordering::SeqCst);
-    let extra_nonce = &format!("{:016x}", new_nonce);
-    let new_blob = template_data.hashing_blob_with_nonce(extra_nonce);
-    match new_blob {
-      Some(new_blob) => Some(Job {
-        id: job_id.to_owned(),
-        hash_type: self.hash_type.clone(),
-        height: template_data.height,
-        difficulty: capped_difficulty,
-        diff_hex: target_hex,
-        hashing_blob: new_blob,
-        template_blob: template_data.blocktemplate_blob.to_owned(),
-        extra_nonce: extra_nonce.to_owned(),
-        reserved_offset: template_data.reserved_offset,
-        network_difficulty: template_data.difficulty,
-        submissions: Default::default(),
-      }),
-      None => None
+    self.build_job(difficulty, format!("{:016x}", new_nonce))
+  }
+
+  /// Creates a job for a proxy, which gets an extra nonce from us like any other job, and fills in
+  /// the rest of the reserved space for its own miners.
+  pub fn get_proxy_job(&self, difficulty: u64) -> Option<Job> {
+    let job = self.get_job(difficulty)?;
+    if job.extra_nonce_offset + RESERVE_SIZE > job.extra.len() {
+      warn!("Block template has no room for proxies to fill in their own nonces");
+      return None;
     }
+    Some(job)
+  }
+
+  fn build_job(&self, difficulty: u64, extra_nonce: String) -> Option<Job> {
+    self.template.read().unwrap().job(&self.hash_type, difficulty, extra_nonce)
   }
 
   /// Refreshes the current template, returning true if there is a new one.
   pub fn fetch_new_template(&self) -> bool {
     let template = self.app.daemon.get_block_template();
     match template {
-      Ok(template) => {
+      Ok((daemon, template)) => {
         if let Some(result) = template.get("result") {
           let parsed_template: StdResult<BlockTemplate, serde_json::Error> =
             serde_json::from_value(result.clone());
           match parsed_template {
-            Ok(new_template) => {
-              let mut current_template = self.template.write().unwrap();
-              if new_template.height > current_template.height {
-                info!("New block template of height {}.", new_template.height);
-                *current_template = new_template;
-                return true;
-              }
+            Ok(mut new_template) => {
+              new_template.daemon = daemon;
+              return self.update_template(new_template);
             },
             Err(err) => error!("Failed to parse block template: {:?}", err),
           }
@@ -201,6 +369,57 @@ impl JobProvider {
     }
     false
   }
+
+  /// Switches miners to a template from the daemon, if it's worth switching to.  Returns true if
+  /// the template was used.
+  pub fn update_template(&self, mut new_template: BlockTemplate) -> bool {
+    if let Err(err) = new_template.parse(&self.app.config.forks) {
+      error!("Failed to parse block template blob: {}", err);
+      return false;
+    }
+    let mut current_template = self.template.write().unwrap();
+    let min_fee_delta = self.app.config.min_fee_delta
+      .unwrap_or(self.app.config.network_transaction_fee);
+    let change = match new_template.change_from(&current_template, min_fee_delta) {
+      Some(change) => change,
+      None => return false,
+    };
+    if change == TemplateChange::Reorganization {
+      warn!("Chain reorganized at height {}, now building on {:?} instead of {:?}",
+            new_template.height, new_template.prev_hash, current_template.prev_hash);
+      let mut orphaned_parents = self.orphaned_parents.lock().unwrap();
+      if let Some(ref orphaned_parent) = current_template.prev_hash {
+        orphaned_parents.insert(orphaned_parent.to_owned(), ());
+      }
+      // The chain could also have switched back to a block that was orphaned earlier.
+      if let Some(ref prev_hash) = new_template.prev_hash {
+        orphaned_parents.remove(prev_hash);
+      }
+    }
+    if change == TemplateChange::MoreFees {
+      info!("Block template reward at height {} went up by {}.", new_template.height,
+            new_template.reward - current_template.reward);
+    } else {
+      info!("New block template of height {}.", new_template.height);
+    }
+    debug!("Template builds on {:?}, with seed hash {:?} and expected reward {:?}",
+           new_template.prev_hash, new_template.seed_hash, new_template.expected_reward);
+    let mut reward_history = self.reward_history.lock().unwrap();
+    if reward_history.len() >= REWARD_HISTORY_LENGTH {
+      reward_history.pop_front();
+    }
+    reward_history.push_back(TemplateReward {
+      time: Utc::now().naive_utc(),
+      height: new_template.height,
+      reward: new_template.reward,
+      change,
+    });
+    if change != TemplateChange::MoreFees {
+      *self.last_new_block.lock().unwrap() = Instant::now();
+    }
+    *current_template = new_template;
+    true
+  }
 }
 
 #[derive(Deserialize, Default)]
@@ -210,37 +429,140 @@ pub struct BlockTemplate {
   difficulty: u64,
   height: u64,
   reserved_offset: u32,
+  /// Newer daemons also send the hashing blob and some details of the block, which older ones and
+  /// many forks leave out.
+  blockhashing_blob: Option<String>,
+  expected_reward: Option<u64>,
+  prev_hash: Option<String>,
+  seed_hash: Option<String>,
+  /// The URL of the daemon that sent the template.
+  #[serde(skip)]
+  daemon: String,
+  /// The parsed blocktemplate_blob, which jobs are built from.
+  #[serde(skip)]
+  block: Option<Arc<Block>>,
+  /// Where the daemon's reserved space starts in the miner transaction's extra field.
+  #[serde(skip)]
+  extra_nonce_offset: usize,
+  /// Only the miner transaction changes between jobs, so the rest of the merkle tree is hashed once
+  /// per template.
+  #[serde(skip)]
+  merkle_branch: Arc<Vec<Vec<u8>>>,
+  /// The cryptonight variant that the block's major version and height call for.
+  #[serde(skip)]
+  variant: u8,
+  /// What the miner transaction pays out, which is the base reward plus fees.
+  #[serde(skip)]
+  reward: u64,
 }
 
 impl BlockTemplate {
-  pub fn hashing_blob_with_nonce(&self, nonce: &str) -> Option<String> {
-    let miner_tx = format!(
-      "{}{}",
-      &self.blocktemplate_blob[BLOCK_HEADER_LENGTH..((self.reserved_offset * 2 - 2) as usize)],
-      nonce
-    );
-    let miner_tx_hash = keccak(&byte_string::string_to_u8_array(&miner_tx))[..32].to_vec();
-    let hex_digits_left = (self.blocktemplate_blob.len() - miner_tx.len()) - BLOCK_HEADER_LENGTH;
-    let mut tx_hashes = Vec::new();
-    tx_hashes.push(miner_tx_hash);
-    let first_transaction_position = self.reserved_offset as usize * 2 + 16;
-    for tx_index in 0..(hex_digits_left / 64) {
-      let start = first_transaction_position + 64 * tx_index;
-      tx_hashes.push(byte_string::string_to_u8_array(&self.blocktemplate_blob[start..(start + 64)]));
+  /// Parses the template blob, finds the reserved space for our extra nonce within it, and works out
+  /// which variant to hash it with.
+  fn parse(&mut self, forks: &[Fork]) -> StdResult<(), String> {
+    let block = Block::parse(&byte_string::string_to_u8_array(&self.blocktemplate_blob))
+      .map_err(|err| format!("{:?}", err))?;
+    // For some reason the reserved offset is 1-indexed, so we have to subtract 1 byte.
+    let reserved_start = (self.reserved_offset as usize).checked_sub(1 + block.extra_offset())
+      .ok_or(format!("Reserved offset {} is before the extra field", self.reserved_offset))?;
+    if reserved_start + EXTRA_NONCE_SIZE > block.miner_tx.extra.len() {
+      return Err(format!("Reserved offset {} is past the extra field", self.reserved_offset));
     }
-    // There is actually a single varint present after the transaction hashes - so even though it
-    // looks like we're ending the above loop at the end of the template, there are 1 or more bytes
-    // left.  It would break our parsing if the varint were 32 bytes, though that is unlikely,
-    // since that varint is a transaction count, and there would need to be 128^32 transactions to
-    // make the varint grow that large.
-    let num_hashes = bytes_to_hex(to_varint(tx_hashes.len()));
-    let root_hash = bytes_to_hex(tree_hash(tx_hashes));
-    return Some(
-      format!("{}{}{}", &self.blocktemplate_blob[..BLOCK_HEADER_LENGTH], &root_hash, &num_hashes)
-    );
+    // We always compute hashing blobs ourselves, since each job has its own extra nonce, but the
+    // daemon's one makes a good check that we're parsing its blocks correctly.
+    if cfg!(debug_assertions) {
+      if let Some(ref expected_blob) = self.blockhashing_blob {
+        let hashing_blob = bytes_to_hex(block.hashing_blob());
+        if &hashing_blob != expected_blob {
+          return Err(format!("Computed hashing blob {} instead of the daemon's {}", hashing_blob,
+                             expected_blob));
+        }
+      }
+    }
+    if self.prev_hash.is_none() {
+      self.prev_hash = Some(bytes_to_hex(block.header.prev_id.to_vec()));
+    }
+    self.extra_nonce_offset = reserved_start;
+    self.merkle_branch = Arc::new(block.merkle_branch());
+    self.reward = block.miner_tx.total_output();
+    let variant = Fork::variant(forks, block.header.major_version, self.height);
+    if variant != self.variant {
+      info!("Hashing with cryptonight variant {} from height {}", variant, self.height);
+    }
+    self.variant = variant;
+    self.block = Some(Arc::new(block));
+    Ok(())
+  }
+
+  /// Whether this template builds on a different block than the current one, at a height that the
+  /// chain had already reached.  That means the daemon switched to another branch of the chain.
+  /// Templates from another daemon never count, since after a failover, the new daemon can simply
+  /// be a block behind the old one.
+  fn reorganizes(&self, current: &BlockTemplate) -> bool {
+    current.block.is_some() && self.daemon == current.daemon && self.height <= current.height &&
+      self.prev_hash != current.prev_hash
+  }
+
+  /// Whether miners should switch from the current template to this one, and why.  Templates at the
+  /// same height are only worth switching to once their fees have grown by min_fee_delta, since
+  /// every switch sends out new jobs to all miners.
+  fn change_from(&self, current: &BlockTemplate, min_fee_delta: u64) -> Option<TemplateChange> {
+    if self.reorganizes(current) {
+      Some(TemplateChange::Reorganization)
+    } else if self.height > current.height {
+      Some(TemplateChange::NewBlock)
+    } else if self.height == current.height &&
+      self.reward >= current.reward + max(min_fee_delta, 1) {
+      Some(TemplateChange::MoreFees)
+    } else {
+      None
+    }
+  }
+
+  fn job(&self, hash_type: &HashType, difficulty: u64, extra_nonce: String) -> Option<Job> {
+    let template = self.block.as_ref()?;
+    let extra = with_extra_nonce(template, self.extra_nonce_offset, &extra_nonce);
+    // The job difficulty typically only exceeds the network difficulty shortly after firing
+    // up a testnet.  Aside from that, sending out jobs higher than the network difficulty would
+    // be unlikely, but undesirable, since it would mean telling miners not to send in completed
+    // blocks.
+    let capped_difficulty = min(difficulty, self.difficulty);
+    Some(Job {
+      id: Uuid::new_v4().to_string(),
+      hash_type: hash_type.clone(),
+      prev_hash: self.prev_hash.clone().unwrap_or_default(),
+      variant: self.variant,
+      height: self.height,
+      difficulty: capped_difficulty,
+      diff_hex: get_target_hex(capped_difficulty),
+      hashing_blob: bytes_to_hex(template.hashing_blob_with_branch(&extra, &self.merkle_branch)),
+      template: template.clone(),
+      extra,
+      extra_nonce,
+      extra_nonce_offset: self.extra_nonce_offset,
+      merkle_branch: self.merkle_branch.clone(),
+      network_difficulty: self.difficulty,
+      submissions: Default::default(),
+    })
+  }
+
+  #[cfg(test)]
+  fn hashing_blob_with_nonce(&self, nonce: &str) -> Option<String> {
+    let template = self.block.as_ref()?;
+    let extra = with_extra_nonce(template, self.extra_nonce_offset, nonce);
+    Some(bytes_to_hex(template.hashing_blob_with_branch(&extra, &self.merkle_branch)))
   }
 }
 
+/// Copies the miner transaction's extra field, with the given hex extra nonce written into it.
+fn with_extra_nonce(block: &Block, extra_nonce_offset: usize, extra_nonce: &str) -> Vec<u8> {
+  let mut extra = block.miner_tx.extra.clone();
+  let extra_nonce = byte_string::string_to_u8_array(extra_nonce);
+  let extra_nonce_end = extra_nonce_offset + extra_nonce.len();
+  extra[extra_nonce_offset..extra_nonce_end].copy_from_slice(&extra_nonce);
+  extra
+}
+
 #[cfg(test)]
 mod tests {
   use blocktemplate::*;
@@ -249,7 +571,7 @@ mod tests {
   fn test_parse_block_template() {
     let test_hashing_blob = "010094fed5d205e42c97122a7b61341c46881837099891d2b2587a0bde019cbae1688e\
       41bc4d70000000005c8e57bea6b5667f77529149756c249904fb346916f7580c18ea64ec793334e903".to_owned();
-    let test_block = BlockTemplate {
+    let mut test_block = BlockTemplate {
       blocktemplate_blob: "010094fed5d205e42c97122a7b61341c46881837099891d2b2587a0bde019cbae1688e41bc\
     4d700000000001e1cf3701ffa5cf3705fbf3b1e40b02d2961caddbcd6294b41030ecf24fadc4229fc45c75df5def56d\
     c1841236db36380f8cce2840202bdba3913153bbbbd8c40a8b9409fe8944bb9964edd905506b558f8eadf027b858080\
@@ -261,7 +583,18 @@ mod tests {
       difficulty: 0,
       height: 0,
       reserved_offset: 285,
+      blockhashing_blob: None,
+      expected_reward: None,
+      prev_hash: None,
+      seed_hash: None,
+      daemon: String::new(),
+      block: None,
+      extra_nonce_offset: 0,
+      merkle_branch: Default::default(),
+      variant: 0,
+      reward: 0,
     };
+    test_block.parse(&[]).unwrap();
     assert_eq!(test_hashing_blob,
                test_block.hashing_blob_with_nonce("0000000000000000").unwrap());
 
@@ -269,7 +602,7 @@ mod tests {
     let empty_block_hashing_blob = "0100a5b6e1d205ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e20859\
       48e8bb148e862b8000000007f8e1bb9aaccac84169ccf9a9a33ac704960e252e05218d19d93a147a396922901"
       .to_owned();
-    let test_empty_block = BlockTemplate {
+    let mut test_empty_block = BlockTemplate {
       blocktemplate_blob: "0100a5b6e1d205ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e2085948e8bb148e8\
     62b80000000001bfd53701ff83d53705e7aee92d0236238d7c671cd670c1e5d145aa38407aa7c4caf78c9c3a5086126\
     c3d1e6d8bd48090dfc04a0288d398bf66e39e28888192a76534060cf698d293d3ed36ba25b23952ee8681a58080dd9d\
@@ -280,8 +613,134 @@ mod tests {
       difficulty: 0,
       height: 0,
       reserved_offset: 283,
+      blockhashing_blob: None,
+      expected_reward: None,
+      prev_hash: None,
+      seed_hash: None,
+      daemon: String::new(),
+      block: None,
+      extra_nonce_offset: 0,
+      merkle_branch: Default::default(),
+      variant: 0,
+      reward: 0,
     };
+    test_empty_block.parse(&[]).unwrap();
     assert_eq!(empty_block_hashing_blob,
                test_empty_block.hashing_blob_with_nonce("0000000000000000").unwrap());
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_reserved_offset() {
+    let mut template = BlockTemplate {
+      blocktemplate_blob: "0100a5b6e1d205ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e2085948e8bb148e8\
+    62b80000000001bfd53701ff83d53705e7aee92d0236238d7c671cd670c1e5d145aa38407aa7c4caf78c9c3a5086126\
+    c3d1e6d8bd48090dfc04a0288d398bf66e39e28888192a76534060cf698d293d3ed36ba25b23952ee8681a58080dd9d\
+    a41702907aeacf368448e675dff25d15f74a2e55ca0155d09a6ee3ff22e9e8231e03e580a094a58d1d028cd86671141\
+    36db4b05fffa7359039243594749b3241cce28a782d2ace58cb1180c0caf384a302020a1e50d39fa6615e3b3a6ca883\
+    bd37a22f3870907bbc1dbbe70c1a6d6b4c1e342b01926d835f688b901dea5d5e2c0df2251a216d769b6cbabaa6fa81f\
+    3797aba88cc0208000000000000000000".to_owned(),
+      difficulty: 1,
+      height: 0,
+      reserved_offset: 283,
+      blockhashing_blob: None,
+      expected_reward: None,
+      prev_hash: None,
+      seed_hash: None,
+      daemon: String::new(),
+      block: None,
+      extra_nonce_offset: 0,
+      merkle_branch: Default::default(),
+      variant: 0,
+      reward: 0,
+    };
+    template.parse(&[]).unwrap();
+    let job = template.job(&HashType::Cryptonight, 1, "0000000000000000".to_owned()).unwrap();
+    assert_eq!(job.reserved_offset(), 282);
+    assert_eq!(job.algo(), "cn/0");
+  }
+
+  #[test]
+  fn test_daemon_hashing_blob() {
+    let template_json = json!({
+      "blocktemplate_blob": "0100a5b6e1d205ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e2085948e8bb\
+        148e862b80000000001bfd53701ff83d53705e7aee92d0236238d7c671cd670c1e5d145aa38407aa7c4caf78c9c3a\
+        5086126c3d1e6d8bd48090dfc04a0288d398bf66e39e28888192a76534060cf698d293d3ed36ba25b23952ee8681a\
+        58080dd9da41702907aeacf368448e675dff25d15f74a2e55ca0155d09a6ee3ff22e9e8231e03e580a094a58d1d02\
+        8cd8667114136db4b05fffa7359039243594749b3241cce28a782d2ace58cb1180c0caf384a302020a1e50d39fa66\
+        15e3b3a6ca883bd37a22f3870907bbc1dbbe70c1a6d6b4c1e342b01926d835f688b901dea5d5e2c0df2251a216d76\
+        9b6cbabaa6fa81f3797aba88cc0208000000000000000000",
+      "blockhashing_blob": "0100a5b6e1d205ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e2085948e8bb14\
+        8e862b8000000007f8e1bb9aaccac84169ccf9a9a33ac704960e252e05218d19d93a147a396922901",
+      "difficulty": 1,
+      "expected_reward": 17592186044415u64,
+      "height": 911269,
+      "prev_hash": "ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e2085948e8bb148e862b8",
+      "reserved_offset": 283,
+    });
+    let mut template: BlockTemplate = serde_json::from_value(template_json.clone()).unwrap();
+    assert_eq!(template.expected_reward, Some(17592186044415));
+    assert_eq!(template.seed_hash, None);
+    template.parse(&[]).unwrap();
+
+    // A daemon that hashes blocks differently from us would have us mining invalid shares.
+    let mut mismatched_json = template_json;
+    mismatched_json["blockhashing_blob"] = json!("0100");
+    let mut mismatched: BlockTemplate = serde_json::from_value(mismatched_json).unwrap();
+    assert!(mismatched.parse(&[]).is_err());
+  }
+
+  #[test]
+  fn test_reorganization() {
+    let template_json = json!({
+      "blocktemplate_blob": "0100a5b6e1d205ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e2085948e8bb\
+        148e862b80000000001bfd53701ff83d53705e7aee92d0236238d7c671cd670c1e5d145aa38407aa7c4caf78c9c3a\
+        5086126c3d1e6d8bd48090dfc04a0288d398bf66e39e28888192a76534060cf698d293d3ed36ba25b23952ee8681a\
+        58080dd9da41702907aeacf368448e675dff25d15f74a2e55ca0155d09a6ee3ff22e9e8231e03e580a094a58d1d02\
+        8cd8667114136db4b05fffa7359039243594749b3241cce28a782d2ace58cb1180c0caf384a302020a1e50d39fa66\
+        15e3b3a6ca883bd37a22f3870907bbc1dbbe70c1a6d6b4c1e342b01926d835f688b901dea5d5e2c0df2251a216d76\
+        9b6cbabaa6fa81f3797aba88cc0208000000000000000000",
+      "difficulty": 1,
+      "height": 911269,
+      "reserved_offset": 283,
+    });
+    let template_with = |height: u64, prev_hash: Option<&str>| {
+      let mut template_json = template_json.clone();
+      template_json["height"] = json!(height);
+      if let Some(prev_hash) = prev_hash {
+        template_json["prev_hash"] = json!(prev_hash);
+      }
+      let mut template: BlockTemplate = serde_json::from_value(template_json).unwrap();
+      template.parse(&[]).unwrap();
+      template
+    };
+    let current = template_with(911269, None);
+    // Without a prev_hash from the daemon, the parent comes from the block header.
+    assert_eq!(current.prev_hash,
+               Some("ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e2085948e8bb148e862b8".to_owned()));
+    let other_parent = "11".repeat(32);
+    assert!(!template_with(911269, None).reorganizes(&current));
+    assert!(!template_with(911270, Some(&other_parent)).reorganizes(&current));
+    assert!(template_with(911269, Some(&other_parent)).reorganizes(&current));
+    assert!(template_with(911268, Some(&other_parent)).reorganizes(&current));
+    assert!(!template_with(911268, Some(&other_parent)).reorganizes(&Default::default()));
+    let mut lagging_daemon = template_with(911268, Some(&other_parent));
+    lagging_daemon.daemon = "http://127.0.0.1:28081/json_rpc".to_owned();
+    assert!(!lagging_daemon.reorganizes(&current));
+    assert_eq!(lagging_daemon.change_from(&current, 1000), None);
+    // Templates at the same height only replace the current one once fees make them worth it.
+    let mut more_fees = template_with(911269, None);
+    assert_eq!(more_fees.reward, current.reward);
+    assert_eq!(more_fees.change_from(&current, 0), None);
+    more_fees.reward += 1000;
+    assert_eq!(more_fees.change_from(&current, 1000), Some(TemplateChange::MoreFees));
+    assert_eq!(more_fees.change_from(&current, 1001), None);
+    assert_eq!(template_with(911270, Some(&other_parent)).change_from(&current, 1000),
+               Some(TemplateChange::NewBlock));
+    assert_eq!(template_with(911269, Some(&other_parent)).change_from(&current, 1000),
+               Some(TemplateChange::Reorganization));
+
+    let job = template_with(911269, Some(&other_parent))
+      .job(&HashType::Cryptonight, 1, "0000000000000000".to_owned()).unwrap();
+    assert_eq!(job.prev_hash, other_parent);
+  }
+}
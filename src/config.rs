@@ -1,36 +1,177 @@
 use std::fs::File;
 use std::io::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use toml;
 
 #[derive(Clone, Deserialize)]
 pub struct Config {
   pub hash_type: String,
+  /// The hard forks that changed the cryptonight variant, which default to Monero's.
+  #[serde(default = "default_forks")]
+  pub forks: Vec<Fork>,
   pub log_level: String,
   pub log_file: String,
-  pub daemon_url: String,
+  /// The daemon to get templates from, when there is only one.
+  pub daemon_url: Option<String>,
+  /// Several daemons to fail over between, which can be listed instead of or as well as daemon_url.
+  #[serde(default)]
+  pub daemons: Vec<DaemonConfig>,
   pub wallet_url: String,
+  /// The daemon's ZMQ publisher, like tcp://127.0.0.1:18083, for hearing about new blocks as soon as
+  /// they arrive.  This needs the zmq feature.
+  pub zmq_url: Option<String>,
   pub payment_mixin: u64,
   pub network_transaction_fee: u64,
+  /// Seconds between sending miners fresh jobs while the template stays the same, 20 by default.
+  pub template_refresh_interval: Option<u64>,
+  /// How much the block reward has to grow from new transaction fees, in atomic units, before
+  /// miners get switched to a new template at the same height.  Defaults to the
+  /// network_transaction_fee.
+  pub min_fee_delta: Option<u64>,
+  /// Seconds between blocks on the coin's network, which defaults to Monero's 120.
+  pub block_target_time: Option<u64>,
+  /// Seconds without a new block before the daemon is considered stuck, ten times the
+  /// block_target_time by default.
+  pub max_template_age: Option<u64>,
+  /// Stops handing out jobs while the daemon looks stuck, rather than having miners work on what is
+  /// probably an old tip.  Jobs that miners already have are dropped, and miners that log in
+  /// meanwhile get no job until the daemon recovers.
+  #[serde(default)]
+  pub freeze_jobs_when_stale: bool,
   pub min_payment: f64,
   pub payment_denomination: f64,
   pub pool_wallet: String,
   pub pool_fee: f64,
+  /// Token that must be sent in the X-Admin-Token header to use the admin API.  The admin API is
+  /// disabled if this is not set.
+  pub admin_token: Option<String>,
+  #[serde(default)]
+  pub api: ApiConfig,
   pub donations: Vec<Donation>,
   pub ports: Vec<ServerConfig>,
 }
 
+/// Where the HTTP API listens, by default on port 8080 of all IPv4 interfaces.
+#[derive(Clone, Deserialize)]
+pub struct ApiConfig {
+  pub address: IpAddr,
+  pub port: u16,
+}
+
+impl Default for ApiConfig {
+  fn default() -> ApiConfig {
+    ApiConfig {
+      address: IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)),
+      port: 8080,
+    }
+  }
+}
+
+/// A hard fork that switched the proof-of-work to another cryptonight variant, from either a block
+/// major version or a height onwards.  Coins don't agree on the major versions, since Monero's
+/// variant 1 fork was version 7, but TurtleCoin's was version 4.
+#[derive(Clone, Deserialize)]
+pub struct Fork {
+  pub variant: u8,
+  pub major_version: Option<u8>,
+  pub height: Option<u64>,
+}
+
+impl Fork {
+  fn reached_by(&self, major_version: u8, height: u64) -> bool {
+    self.major_version.map_or(false, |fork_version| major_version >= fork_version) ||
+      self.height.map_or(false, |fork_height| height >= fork_height)
+  }
+
+  /// The variant that blocks with this major version and height are hashed with: the highest one
+  /// whose fork they've reached, or the original variant 0 before any fork.
+  pub fn variant(forks: &[Fork], major_version: u8, height: u64) -> u8 {
+    forks.iter()
+      .filter(|fork| fork.reached_by(major_version, height))
+      .map(|fork| fork.variant)
+      .max()
+      .unwrap_or(0)
+  }
+}
+
+fn default_forks() -> Vec<Fork> {
+  vec![Fork {
+    variant: 1,
+    major_version: Some(7),
+    height: None,
+  }]
+}
+
+/// A daemon to get templates from.  The healthy daemon with the lowest priority number is used, and
+/// found blocks go to every healthy daemon.
+#[derive(Clone, Deserialize)]
+pub struct DaemonConfig {
+  pub url: String,
+  #[serde(default)]
+  pub priority: u32,
+}
+
 #[derive(Clone, Deserialize)]
 pub struct Donation {
   pub address: String,
   pub percentage: f64,
 }
 
+#[derive(Clone, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+  Tcp,
+  WebSocket,
+}
+
+impl Default for Transport {
+  fn default() -> Transport {
+    Transport::Tcp
+  }
+}
+
 #[derive(Clone, Deserialize)]
 pub struct ServerConfig {
   pub port: u16,
+  /// The addresses to listen on, which can be IPv4 or IPv6.  Defaults to all IPv4 interfaces.
+  #[serde(default)]
+  pub bind: Vec<IpAddr>,
   pub starting_difficulty: u64,
   pub target_time: u64,
+  /// Either "tcp" for regular stratum, or "websocket" for browser miners.
+  #[serde(default)]
+  pub transport: Transport,
   pub max_connections: Option<usize>,
+  /// Limits on the sessions that one wallet address, or one IP, can have open on this port.
+  pub max_connections_per_address: Option<usize>,
+  pub max_connections_per_ip: Option<usize>,
+  /// Seconds that a miner can go without sending a request before its connection gets closed,
+  /// 600 by default.
+  pub idle_timeout: Option<u64>,
+  /// Seconds that a new connection has to send its first request, 30 by default.
+  pub first_request_timeout: Option<u64>,
+  /// Vardiff never goes below this difficulty, and neither do difficulties requested at login.
+  pub min_difficulty: Option<u64>,
+  /// The highest difficulty that vardiff or a miner's login can set.
+  pub max_difficulty: Option<u64>,
+  /// How many seconds of shares vardiff uses to estimate a miner's hashrate, 120 by default.
+  pub vardiff_window: Option<u64>,
+  /// How far off the ideal difficulty a miner's difficulty can be before vardiff changes it, as a
+  /// fraction.  Defaults to 0.25.
+  pub variance_tolerance: Option<f64>,
+  /// Leaves the top nonce byte to the miner, for NiceHash and similar marketplaces.  Shares can
+  /// have any value there, see Job::check_submission.
+  #[serde(default)]
+  pub nicehash: bool,
+  /// Serves stratum+ssl on this port, using the PEM files at tls_certificate and tls_key.
+  #[serde(default)]
+  pub tls: bool,
+  pub tls_certificate: Option<String>,
+  pub tls_key: Option<String>,
+  /// Expects a PROXY protocol header (v1 or v2) at the start of each connection, for ports behind a
+  /// TCP load balancer.  Bans and connection limits then apply to the miner's own address.
+  #[serde(default)]
+  pub proxy_protocol: bool,
 }
 
 pub fn read_config() -> Config {
@@ -38,5 +179,64 @@ pub fn read_config() -> Config {
   let mut contents = String::new();
   f.read_to_string(&mut contents)
     .expect("something went wrong reading the config file");
-  toml::from_str(&contents).unwrap()
+  let config: Config = toml::from_str(&contents).unwrap();
+  for fork in &config.forks {
+    if fork.variant > 1 {
+      panic!("Cryptonight variant {} in config.toml is not supported", fork.variant);
+    }
+    if fork.major_version.is_none() && fork.height.is_none() {
+      panic!("Forks in config.toml need either a major_version or a height");
+    }
+  }
+  if config.daemon_configs().is_empty() {
+    panic!("config.toml needs a daemon_url or a list of daemons");
+  }
+  config
+}
+
+impl Config {
+  /// Every configured daemon, with daemon_url as the first choice.
+  pub fn daemon_configs(&self) -> Vec<DaemonConfig> {
+    let mut daemons = Vec::new();
+    if let Some(ref url) = self.daemon_url {
+      daemons.push(DaemonConfig {
+        url: url.to_owned(),
+        priority: 0,
+      });
+    }
+    daemons.extend(self.daemons.iter().cloned());
+    daemons
+  }
+}
+
+impl ServerConfig {
+  pub fn bind_addresses(&self) -> Vec<SocketAddr> {
+    if self.bind.is_empty() {
+      vec![SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), self.port)]
+    } else {
+      self.bind.iter().map(|ip| SocketAddr::new(*ip, self.port)).collect()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use config::*;
+
+  #[test]
+  fn test_fork_variants() {
+    let monero = default_forks();
+    assert_eq!(Fork::variant(&monero, 6, 1546000), 0);
+    assert_eq!(Fork::variant(&monero, 7, 1546000), 1);
+    assert_eq!(Fork::variant(&monero, 8, 1546000), 1);
+
+    let by_height = vec![Fork {
+      variant: 1,
+      major_version: None,
+      height: Some(187000),
+    }];
+    assert_eq!(Fork::variant(&by_height, 4, 186999), 0);
+    assert_eq!(Fork::variant(&by_height, 4, 187000), 1);
+    assert_eq!(Fork::variant(&[], 7, 187000), 0);
+  }
 }
@@ -8,6 +8,44 @@ pub enum HashType {
   CryptonightLite,
 }
 
+impl HashType {
+  /// The name of the algorithm that we hash shares with, as used by xmrig's algo extension.
+  pub fn algo(&self, variant: u8) -> &'static str {
+    match (self, variant) {
+      (&HashType::Cryptonight, 0) => "cn/0",
+      (&HashType::Cryptonight, _) => "cn/1",
+      (&HashType::CryptonightLite, 0) => "cn-lite/0",
+      (&HashType::CryptonightLite, _) => "cn-lite/1",
+    }
+  }
+
+  /// Checks whether a miner that announced these algorithms at login can mine our jobs.  Miners may
+  /// list a whole family (like "cn" or the older "cryptonight"), which means they'll follow the
+  /// variant that we send with each job.
+  pub fn supported_by(&self, variant: u8, algos: &[&str]) -> bool {
+    let (families, long_name): (&[&str], &str) = match self {
+      &HashType::Cryptonight => (&["cn", "cryptonight"], "cryptonight"),
+      &HashType::CryptonightLite => (&["cn-lite", "cryptonight-lite"], "cryptonight-lite"),
+    };
+    let long_algo = format!("{}/{}", long_name, variant);
+    algos.iter().any(|algo| {
+      let algo = algo.to_lowercase();
+      algo == self.algo(variant) || algo == long_algo || families.contains(&algo.as_str())
+    })
+  }
+}
+
+/// The version of cryptonight that mithril implements for a variant.  Variant 0 is the original
+/// algorithm, and variant 1 is the tweak from Monero's April 2018 hard fork.
+pub fn hash_version(variant: u8) -> hash::HashVersion {
+  if variant == 0 {
+    hash::HashVersion::Version6
+  }
+  else {
+    hash::HashVersion::Version7
+  }
+}
+
 pub fn bytes_to_hex(bytes: Vec<u8>) -> String {
   let hexes: Vec<String> = bytes.iter()
     .map(|b| format!("{:02x}", b))
@@ -23,6 +61,56 @@ pub fn cn_hash(input: &Vec<u8>, hash_type: &HashType, version: hash::HashVersion
   }
 }
 
+/// The hashes that the first transaction, the miner transaction, gets combined with on its way up
+/// to the root of tree_hash, given the hashes of all the other transactions.  These don't depend
+/// on the miner transaction, so a template's branch can be computed once and reused for every
+/// extra nonce.
+pub fn tree_branch(other_hashes: &[Vec<u8>]) -> Vec<Vec<u8>> {
+  let count = other_hashes.len() + 1;
+  let mut branch = Vec::new();
+  if count == 1 {
+    return branch;
+  } else if count == 2 {
+    branch.push(other_hashes[0].clone());
+    return branch;
+  }
+  // This follows tree_hash, except that the first hash in each level stands for the one on the
+  // miner transaction's path, which we don't know, so it's left empty.
+  let mut cnt = tree_hash_cnt(count);
+  let slice_point = 2 * cnt - count;
+  let hash = |i: usize| if i == 0 { &[][..] } else { &other_hashes[i - 1][..] };
+  let mut ints: Vec<Vec<u8>> = (0..cnt).map(|_| Vec::new()).collect();
+  for i in 1..slice_point {
+    ints[i] = hash(i).to_vec();
+  }
+  let mut i = slice_point;
+  for j in slice_point..cnt {
+    if j == 0 {
+      // With no hashes carried over as-is, the miner transaction is paired up straight away.
+      branch.push(hash(1).to_vec());
+    } else {
+      ints[j] = concat_and_hash(hash(i), hash(i + 1));
+    }
+    i += 2;
+  }
+  while cnt > 2 {
+    branch.push(ints[1].clone());
+    cnt /= 2;
+    let mut ii = 2;
+    for jj in 1..cnt {
+      ints[jj] = concat_and_hash(&ints[ii], &ints[ii + 1]);
+      ii += 2;
+    }
+  }
+  branch.push(ints[1].clone());
+  branch
+}
+
+/// Computes tree_hash from the miner transaction's hash and its branch.
+pub fn tree_hash_from_branch(first_hash: &[u8], branch: &[Vec<u8>]) -> Vec<u8> {
+  branch.iter().fold(first_hash.to_vec(), |hash, sibling| concat_and_hash(&hash, sibling))
+}
+
 /// Returns a representation of the miner's current difficulty, in a hex format which is sort of
 /// a quirk of the stratum protocol.
 pub fn get_target_hex(difficulty: u64) -> String {
@@ -159,6 +247,20 @@ mod tests {
   }
 
 
+  #[test]
+  fn test_algo_support() {
+    assert!(HashType::Cryptonight.supported_by(1, &["cn/1"]));
+    assert!(HashType::Cryptonight.supported_by(1, &["cn/0", "cryptonight"]));
+    assert!(HashType::Cryptonight.supported_by(1, &["cn-lite/1", "CN"]));
+    assert!(HashType::Cryptonight.supported_by(1, &["cryptonight/1"]));
+    assert!(!HashType::Cryptonight.supported_by(1, &["cn/0", "cn-lite"]));
+    assert!(HashType::Cryptonight.supported_by(0, &["cn/0"]));
+    assert!(!HashType::Cryptonight.supported_by(0, &["cn/1", "cryptonight/1"]));
+    assert!(!HashType::Cryptonight.supported_by(1, &[]));
+    assert!(HashType::CryptonightLite.supported_by(1, &["cn-lite/1"]));
+    assert!(!HashType::CryptonightLite.supported_by(1, &["cn/1", "cryptonight"]));
+  }
+
   #[test]
   fn test_varint() {
     assert_eq!(from_varint(&[42]), (42, 1));
@@ -202,4 +304,15 @@ mod tests {
     let test_tree_hash = bytes_to_hex(tree_hash(concat_hash_tests));
     assert_eq!("2d0ad2566627b50cd45125e89e963433b212b368cd2d91662c44813ba9ec90c2", test_tree_hash);
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_tree_branch() {
+    use crypto::longkeccak::keccak;
+    let hashes: Vec<Vec<u8>> = (0..40u8).map(|i| keccak(&[i])[..32].to_vec()).collect();
+    for count in 1..hashes.len() {
+      let branch = tree_branch(&hashes[1..count]);
+      assert_eq!(tree_hash_from_branch(&hashes[0], &branch), tree_hash(hashes[..count].to_vec()),
+                 "Branch doesn't match tree_hash for {} hashes", count);
+    }
+  }
+}
@@ -0,0 +1,497 @@
+//! Parsing and serialization for the parts of a CryptoNote block that the pool works with: the
+//! header, the miner transaction, and the hashes of the other transactions.  This is enough to fill
+//! in extra nonces and miner nonces, and to build the hashing blob, without relying on anything
+//! being at a fixed offset.
+
+use crypto::longkeccak::keccak;
+use crypto::cryptonote_utils::{tree_hash, tree_branch, tree_hash_from_branch};
+
+pub type Hash = [u8; 32];
+
+/// The tag for the only kind of input that a miner transaction has.
+const TXIN_GEN: u8 = 0xff;
+const TXOUT_TO_KEY: u8 = 0x02;
+/// Outputs with a view tag, from Monero's v15 hard fork.
+const TXOUT_TO_TAGGED_KEY: u8 = 0x03;
+/// Miner transactions never have ring signatures, so they always use this RingCT type.
+const RCT_TYPE_NULL: u8 = 0;
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+  UnexpectedEnd,
+  VarintOverflow,
+  UnsupportedInput(u8),
+  UnsupportedOutput(u8),
+  UnsupportedRctType(u8),
+  /// The blob had bytes left over after the block, holding the number of extra bytes.
+  TrailingBytes(usize),
+}
+
+struct Reader<'a> {
+  bytes: &'a [u8],
+  position: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn byte(&mut self) -> Result<u8, ParseError> {
+    let byte = *self.bytes.get(self.position).ok_or(ParseError::UnexpectedEnd)?;
+    self.position += 1;
+    Ok(byte)
+  }
+
+  fn bytes(&mut self, length: usize) -> Result<&'a [u8], ParseError> {
+    if self.bytes.len() - self.position < length {
+      return Err(ParseError::UnexpectedEnd);
+    }
+    let bytes = &self.bytes[self.position..(self.position + length)];
+    self.position += length;
+    Ok(bytes)
+  }
+
+  fn hash(&mut self) -> Result<Hash, ParseError> {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(self.bytes(32)?);
+    Ok(hash)
+  }
+
+  /// Reads a varint, see cryptonote_utils::from_varint for how they work.
+  fn varint(&mut self) -> Result<u64, ParseError> {
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    loop {
+      let byte = self.byte()?;
+      if shift > 63 || (shift == 63 && byte > 1) {
+        return Err(ParseError::VarintOverflow);
+      }
+      value |= ((byte & 0x7f) as u64) << shift;
+      if byte < 0x80 {
+        return Ok(value);
+      }
+      shift += 7;
+    }
+  }
+
+  fn varint_u8(&mut self) -> Result<u8, ParseError> {
+    let value = self.varint()?;
+    if value > 0xff {
+      return Err(ParseError::VarintOverflow);
+    }
+    Ok(value as u8)
+  }
+}
+
+pub fn write_varint(out: &mut Vec<u8>, value: u64) {
+  let mut remaining = value;
+  while remaining >= 0x80 {
+    out.push((remaining & 0x7f) as u8 | 0x80);
+    remaining >>= 7;
+  }
+  out.push(remaining as u8);
+}
+
+fn fast_hash(bytes: &[u8]) -> Hash {
+  let mut hash = [0u8; 32];
+  hash.copy_from_slice(&keccak(bytes)[..32]);
+  hash
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockHeader {
+  pub major_version: u8,
+  pub minor_version: u8,
+  pub timestamp: u64,
+  pub prev_id: Hash,
+  pub nonce: u32,
+}
+
+impl BlockHeader {
+  fn read(reader: &mut Reader) -> Result<BlockHeader, ParseError> {
+    let major_version = reader.varint_u8()?;
+    let minor_version = reader.varint_u8()?;
+    let timestamp = reader.varint()?;
+    let prev_id = reader.hash()?;
+    let nonce_bytes = reader.bytes(4)?;
+    let nonce = nonce_bytes.iter().rev().fold(0u32, |nonce, byte| (nonce << 8) | *byte as u32);
+    Ok(BlockHeader {
+      major_version,
+      minor_version,
+      timestamp,
+      prev_id,
+      nonce,
+    })
+  }
+
+  fn write(&self, out: &mut Vec<u8>) {
+    write_varint(out, self.major_version as u64);
+    write_varint(out, self.minor_version as u64);
+    write_varint(out, self.timestamp);
+    out.extend_from_slice(&self.prev_id);
+    for i in 0..4 {
+      out.push((self.nonce >> (8 * i)) as u8);
+    }
+  }
+
+  pub fn serialize(&self) -> Vec<u8> {
+    let mut out = Vec::new();
+    self.write(&mut out);
+    out
+  }
+
+  /// Where the nonce starts in the serialized header, which is also where miners put it in the
+  /// hashing blob.  The varints before it mean that this isn't the same for every block.
+  pub fn nonce_offset(&self) -> usize {
+    self.serialize().len() - 4
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxIn {
+  /// The coinbase input, which creates the block reward at the given height.
+  Gen { height: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TxOutTarget {
+  Key(Hash),
+  TaggedKey { key: Hash, view_tag: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxOut {
+  pub amount: u64,
+  pub target: TxOutTarget,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MinerTransaction {
+  pub version: u64,
+  pub unlock_time: u64,
+  pub inputs: Vec<TxIn>,
+  pub outputs: Vec<TxOut>,
+  /// Holds the transaction public key, and the space that the daemon reserves for our extra nonce.
+  pub extra: Vec<u8>,
+}
+
+impl MinerTransaction {
+  fn read(reader: &mut Reader) -> Result<MinerTransaction, ParseError> {
+    let version = reader.varint()?;
+    let unlock_time = reader.varint()?;
+    let input_count = reader.varint()?;
+    let mut inputs = Vec::new();
+    for _ in 0..input_count {
+      match reader.byte()? {
+        TXIN_GEN => inputs.push(TxIn::Gen { height: reader.varint()? }),
+        tag => return Err(ParseError::UnsupportedInput(tag)),
+      }
+    }
+    let output_count = reader.varint()?;
+    let mut outputs = Vec::new();
+    for _ in 0..output_count {
+      let amount = reader.varint()?;
+      let target = match reader.byte()? {
+        TXOUT_TO_KEY => TxOutTarget::Key(reader.hash()?),
+        TXOUT_TO_TAGGED_KEY => TxOutTarget::TaggedKey {
+          key: reader.hash()?,
+          view_tag: reader.byte()?,
+        },
+        tag => return Err(ParseError::UnsupportedOutput(tag)),
+      };
+      outputs.push(TxOut {
+        amount,
+        target,
+      });
+    }
+    let extra_length = reader.varint()? as usize;
+    let extra = reader.bytes(extra_length)?.to_vec();
+    if version >= 2 {
+      let rct_type = reader.byte()?;
+      if rct_type != RCT_TYPE_NULL {
+        return Err(ParseError::UnsupportedRctType(rct_type));
+      }
+    }
+    Ok(MinerTransaction {
+      version,
+      unlock_time,
+      inputs,
+      outputs,
+      extra,
+    })
+  }
+
+  /// Writes everything up to the contents of an extra field of extra_length bytes, which is where
+  /// the reserved space is measured from.
+  fn write_before_extra(&self, out: &mut Vec<u8>, extra_length: usize) {
+    write_varint(out, self.version);
+    write_varint(out, self.unlock_time);
+    write_varint(out, self.inputs.len() as u64);
+    for input in self.inputs.iter() {
+      match input {
+        &TxIn::Gen { height } => {
+          out.push(TXIN_GEN);
+          write_varint(out, height);
+        },
+      }
+    }
+    write_varint(out, self.outputs.len() as u64);
+    for output in self.outputs.iter() {
+      write_varint(out, output.amount);
+      match output.target {
+        TxOutTarget::Key(ref key) => {
+          out.push(TXOUT_TO_KEY);
+          out.extend_from_slice(key);
+        },
+        TxOutTarget::TaggedKey { ref key, view_tag } => {
+          out.push(TXOUT_TO_TAGGED_KEY);
+          out.extend_from_slice(key);
+          out.push(view_tag);
+        },
+      }
+    }
+    write_varint(out, extra_length as u64);
+  }
+
+  /// The write functions take the extra field separately, so that jobs can share one parsed
+  /// template and keep only their own extra field.
+  fn write_prefix(&self, out: &mut Vec<u8>, extra: &[u8]) {
+    self.write_before_extra(out, extra.len());
+    out.extend_from_slice(extra);
+  }
+
+  fn write(&self, out: &mut Vec<u8>, extra: &[u8]) {
+    self.write_prefix(out, extra);
+    if self.version >= 2 {
+      out.push(RCT_TYPE_NULL);
+    }
+  }
+
+  /// The block reward, including the fees of every transaction in the block.
+  pub fn total_output(&self) -> u64 {
+    self.outputs.iter().map(|output| output.amount).sum()
+  }
+
+  /// The transaction ID.  Version 1 transactions hash their whole serialization, while version 2
+  /// hashes the prefix and the RingCT parts separately, and then hashes the three hashes together.
+  pub fn hash(&self) -> Hash {
+    self.hash_with_extra(&self.extra)
+  }
+
+  /// The ID that the transaction would have with a different extra field.
+  pub fn hash_with_extra(&self, extra: &[u8]) -> Hash {
+    if self.version < 2 {
+      let mut serialized = Vec::new();
+      self.write(&mut serialized, extra);
+      return fast_hash(&serialized);
+    }
+    let mut prefix = Vec::new();
+    self.write_prefix(&mut prefix, extra);
+    let mut hashes = fast_hash(&prefix).to_vec();
+    hashes.extend_from_slice(&fast_hash(&[RCT_TYPE_NULL]));
+    // A null RingCT signature has nothing prunable, which is hashed as all zeroes.
+    hashes.extend_from_slice(&[0u8; 32]);
+    fast_hash(&hashes)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+  pub header: BlockHeader,
+  pub miner_tx: MinerTransaction,
+  pub tx_hashes: Vec<Hash>,
+}
+
+impl Block {
+  pub fn parse(bytes: &[u8]) -> Result<Block, ParseError> {
+    let mut reader = Reader {
+      bytes,
+      position: 0,
+    };
+    let header = BlockHeader::read(&mut reader)?;
+    let miner_tx = MinerTransaction::read(&mut reader)?;
+    let tx_count = reader.varint()?;
+    let mut tx_hashes = Vec::new();
+    for _ in 0..tx_count {
+      tx_hashes.push(reader.hash()?);
+    }
+    if reader.position < bytes.len() {
+      return Err(ParseError::TrailingBytes(bytes.len() - reader.position));
+    }
+    Ok(Block {
+      header,
+      miner_tx,
+      tx_hashes,
+    })
+  }
+
+  pub fn serialize(&self) -> Vec<u8> {
+    self.serialize_with_extra(&self.miner_tx.extra)
+  }
+
+  /// The serialized block, with a different extra field in the miner transaction.
+  pub fn serialize_with_extra(&self, extra: &[u8]) -> Vec<u8> {
+    let mut out = self.header.serialize();
+    self.miner_tx.write(&mut out, extra);
+    write_varint(&mut out, self.tx_hashes.len() as u64);
+    for tx_hash in self.tx_hashes.iter() {
+      out.extend_from_slice(tx_hash);
+    }
+    out
+  }
+
+  /// Where the miner transaction's extra field starts in the serialized block.
+  pub fn extra_offset(&self) -> usize {
+    let mut out = self.header.serialize();
+    self.miner_tx.write_before_extra(&mut out, self.miner_tx.extra.len());
+    out.len()
+  }
+
+  /// What miners hash: the header, followed by the merkle root of all the transactions, and the
+  /// number of transactions including the miner transaction.
+  pub fn hashing_blob(&self) -> Vec<u8> {
+    let mut hashes = vec![self.miner_tx.hash().to_vec()];
+    hashes.extend(self.tx_hashes.iter().map(|tx_hash| tx_hash.to_vec()));
+    let mut blob = self.header.serialize();
+    blob.extend(tree_hash(hashes));
+    write_varint(&mut blob, self.tx_hashes.len() as u64 + 1);
+    blob
+  }
+
+  /// The merkle branch for the miner transaction, which stays the same whatever goes into the
+  /// miner transaction's extra field.
+  pub fn merkle_branch(&self) -> Vec<Vec<u8>> {
+    let tx_hashes: Vec<Vec<u8>> = self.tx_hashes.iter().map(|tx_hash| tx_hash.to_vec()).collect();
+    tree_branch(&tx_hashes)
+  }
+
+  /// The same as hashing_blob, but with a different extra field in the miner transaction.  Only the
+  /// miner transaction is hashed, with the rest of the merkle tree coming from merkle_branch.
+  pub fn hashing_blob_with_branch(&self, extra: &[u8], branch: &[Vec<u8>]) -> Vec<u8> {
+    let mut blob = self.header.serialize();
+    blob.extend(tree_hash_from_branch(&self.miner_tx.hash_with_extra(extra), branch));
+    write_varint(&mut blob, self.tx_hashes.len() as u64 + 1);
+    blob
+  }
+
+  /// The block ID, which is the fast hash of the hashing blob, prefixed with its length.
+  pub fn id(&self) -> Hash {
+    let hashing_blob = self.hashing_blob();
+    let mut input = Vec::new();
+    write_varint(&mut input, hashing_blob.len() as u64);
+    input.extend(hashing_blob);
+    fast_hash(&input)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use cryptonote::*;
+  use crypto::cryptonote_utils::bytes_to_hex;
+  use mithril::byte_string;
+
+  const TEST_BLOCK: &str = "010094fed5d205e42c97122a7b61341c46881837099891d2b2587a0bde019cbae1688e41bc\
+    4d700000000001e1cf3701ffa5cf3705fbf3b1e40b02d2961caddbcd6294b41030ecf24fadc4229fc45c75df5def56d\
+    c1841236db36380f8cce2840202bdba3913153bbbbd8c40a8b9409fe8944bb9964edd905506b558f8eadf027b858080\
+    dd9da41702625f0a1c55924dedd94ae36929cfb99664176ff1d6417abfdc5bfb40daf20b9380a094a58d1d027151b66\
+    783aa0ed7d3531dcc35b958945491922222327f9bd57693a18b252a6a80c0caf384a302022c8848debdf1f00e5f6a47\
+    f0886e5caf027c8fd7e159277f1aa6c5a3796e49ca2b01bdcff031f0dd952991227c05512204eb76400cd8a06c30458\
+    31783cd6fbdb9f50208000000000000000002cde625408d94764cf5244bff45ddb0f8d6d42d02b8c6afb99ae9dff33a\
+    7bfcacae531ddf666352c45b25569c8d894ed8a327d9fb3c361ed0e7e0433190fe9fec";
+
+  #[test]
+  fn test_varint_round_trip() {
+    for value in [0, 1, 127, 128, 300, 1 << 35, u64::max_value()].iter() {
+      let mut out = Vec::new();
+      write_varint(&mut out, *value);
+      let mut reader = Reader {
+        bytes: &out,
+        position: 0,
+      };
+      assert_eq!(reader.varint(), Ok(*value));
+      assert_eq!(reader.position, out.len());
+    }
+    let mut reader = Reader {
+      bytes: &[0xff; 11],
+      position: 0,
+    };
+    assert_eq!(reader.varint(), Err(ParseError::VarintOverflow));
+  }
+
+  #[test]
+  fn test_parse_block() {
+    let bytes = byte_string::string_to_u8_array(TEST_BLOCK);
+    let block = Block::parse(&bytes).unwrap();
+    assert_eq!(block.header.major_version, 1);
+    assert_eq!(block.header.minor_version, 0);
+    assert_eq!(block.header.nonce, 0);
+    assert_eq!(block.header.nonce_offset(), 39);
+    assert_eq!(block.miner_tx.version, 1);
+    assert_eq!(block.miner_tx.inputs, vec![TxIn::Gen { height: 911269 }]);
+    assert_eq!(block.miner_tx.outputs.len(), 5);
+    assert_eq!(block.tx_hashes.len(), 2);
+    assert_eq!(block.serialize(), bytes);
+    // The daemon's reserved space is in the extra field, after the transaction public key.
+    assert_eq!(block.extra_offset(), 249);
+    assert_eq!(bytes_to_hex(block.hashing_blob()),
+               "010094fed5d205e42c97122a7b61341c46881837099891d2b2587a0bde019cbae1688e41bc4d700000000\
+                05c8e57bea6b5667f77529149756c249904fb346916f7580c18ea64ec793334e903");
+    assert_eq!(block.hashing_blob_with_branch(&block.miner_tx.extra, &block.merkle_branch()),
+               block.hashing_blob());
+    // Filling in the reserved space gives the same block as writing it into the extra field.
+    let mut extra = block.miner_tx.extra.clone();
+    let extra_length = extra.len();
+    extra[(extra_length - 8)..].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+    let mut filled_in = block.clone();
+    filled_in.miner_tx.extra = extra.clone();
+    assert_eq!(block.serialize_with_extra(&extra), filled_in.serialize());
+    assert_eq!(block.hashing_blob_with_branch(&extra, &block.merkle_branch()),
+               filled_in.hashing_blob());
+  }
+
+  #[test]
+  fn test_miner_tx_v2_round_trip() {
+    let miner_tx = MinerTransaction {
+      version: 2,
+      unlock_time: 1234567,
+      inputs: vec![TxIn::Gen { height: 1234507 }],
+      outputs: vec![
+        TxOut {
+          amount: 4567890123,
+          target: TxOutTarget::Key([7u8; 32]),
+        },
+        TxOut {
+          amount: 0,
+          target: TxOutTarget::TaggedKey {
+            key: [9u8; 32],
+            view_tag: 0x42,
+          },
+        },
+      ],
+      extra: vec![0x01, 0xaa, 0x02, 0x08, 0, 0, 0, 0, 0, 0, 0, 0],
+    };
+    let block = Block {
+      header: BlockHeader {
+        major_version: 16,
+        minor_version: 16,
+        timestamp: 1655000000,
+        prev_id: [3u8; 32],
+        nonce: 0x12345678,
+      },
+      miner_tx,
+      tx_hashes: vec![[5u8; 32]],
+    };
+    let bytes = block.serialize();
+    assert_eq!(&bytes[..2], &[16, 16]);
+    assert_eq!(&bytes[(block.header.nonce_offset())..(block.header.nonce_offset() + 4)],
+               &[0x78, 0x56, 0x34, 0x12]);
+    assert_eq!(*bytes.iter().rev().nth(32).unwrap(), 1);
+    assert_eq!(Block::parse(&bytes), Ok(block));
+  }
+
+  #[test]
+  fn test_parse_errors() {
+    let bytes = byte_string::string_to_u8_array(TEST_BLOCK);
+    assert_eq!(Block::parse(&bytes[..100]), Err(ParseError::UnexpectedEnd));
+    let mut trailing = bytes.to_vec();
+    trailing.push(0);
+    assert_eq!(Block::parse(&trailing), Err(ParseError::TrailingBytes(1)));
+  }
+}
@@ -1,8 +1,13 @@
+use std::fmt;
 use std::sync::*;
+use std::sync::atomic::*;
+use std::sync::mpsc;
+use std::thread;
 use jsonrpc_core::*;
 use reqwest;
-use std::result::Result;
+use std::result::Result as StdResult;
 use config::Config;
+use blocktemplate::RESERVE_SIZE;
 
 #[derive(Serialize, Debug)]
 pub struct Transfer {
@@ -24,31 +29,254 @@ pub struct BlockHeader {
   pub depth: u64,
 }
 
+/// What a daemon's get_info said about its view of the chain.
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeInfo {
+  pub height: u64,
+  /// The height that the daemon's peers have reached, when it knows.
+  pub target_height: Option<u64>,
+  pub synchronized: bool,
+}
+
+/// The last health check of a daemon.
+#[derive(Clone, Serialize)]
+pub struct DaemonStatus {
+  pub url: String,
+  pub priority: u32,
+  pub healthy: bool,
+  pub height: Option<u64>,
+  pub target_height: Option<u64>,
+  pub error: Option<String>,
+}
+
+/// Why a call to a daemon failed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DaemonError {
+  /// The daemon couldn't be reached, or didn't answer with JSON.  Another daemon might do better.
+  Transport(String),
+  /// The daemon answered with a JSON-RPC error, which the other daemons would most likely give too.
+  Rpc(String),
+}
+
+impl fmt::Display for DaemonError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &DaemonError::Transport(ref message) | &DaemonError::Rpc(ref message) => f.write_str(message),
+    }
+  }
+}
+
 pub struct DaemonClient {
   config: Arc<Config>,
+  /// The configured daemons, in order of priority.
+  daemons: Vec<RwLock<DaemonStatus>>,
+  /// The daemon that templates currently come from.
+  active: AtomicUsize,
+}
+
+/// Seconds between checks on the health of each daemon.
+pub const HEALTH_CHECK_INTERVAL: u64 = 10;
+
+/// Daemons that are more than this many blocks behind the others are treated as unhealthy, since
+/// their templates would only produce orphans.
+const MAX_HEIGHT_LAG: u64 = 1;
+
+/// Decides which daemons are healthy, given what each one's get_info returned.  A daemon has to be
+/// reachable, synchronized, and within MAX_HEIGHT_LAG of the highest daemon.
+pub fn assess_health(infos: &[Option<NodeInfo>]) -> Vec<bool> {
+  let top_height = infos.iter()
+    .filter_map(|info| info.as_ref())
+    .map(|info| info.height)
+    .max()
+    .unwrap_or(0);
+  infos.iter().map(|info| match info {
+    &Some(ref info) => info.synchronized && info.height + MAX_HEIGHT_LAG >= top_height,
+    &None => false,
+  }).collect()
+}
+
+/// Checks the daemons' last health checks for signs that the one we use has fallen behind the
+/// network: either no daemon is healthy, or the active daemon's peers are further along than it is.
+pub fn sync_problem(statuses: &[DaemonStatus], active: usize) -> Option<String> {
+  if !statuses.iter().any(|status| status.healthy) {
+    return Some("No daemon is healthy".to_owned());
+  }
+  let active = &statuses[active];
+  match (active.height, active.target_height) {
+    (Some(height), Some(target_height)) if target_height > height + MAX_HEIGHT_LAG => {
+      Some(format!("Daemon {} is at height {}, but its peers are at {}", active.url, height,
+                   target_height))
+    },
+    _ => None,
+  }
+}
+
+/// The order to try daemons in: the active one first, then the other healthy daemons in order of
+/// priority, with the unhealthy ones as the last resort.
+pub fn failover_order(active: usize, healthy: &[bool]) -> Vec<usize> {
+  let mut candidates = vec![active];
+  candidates.extend((0..healthy.len()).filter(|&i| i != active && healthy[i]));
+  candidates.extend((0..healthy.len()).filter(|&i| i != active && !healthy[i]));
+  candidates
 }
 
-/// Handles calls to the monero/aeon/etc. network, via the configured daemon_url.
+/// Handles calls to the monero/aeon/etc. network, via the configured daemons, failing over between
+/// them.
 impl DaemonClient {
   pub fn new(config: Arc<Config>) -> DaemonClient {
+    let mut daemon_configs = config.daemon_configs();
+    daemon_configs.sort_by_key(|daemon| daemon.priority);
+    let daemons = daemon_configs.into_iter().map(|daemon| RwLock::new(DaemonStatus {
+      url: daemon.url,
+      priority: daemon.priority,
+      // Until the first health check, every daemon gets the benefit of the doubt.
+      healthy: true,
+      height: None,
+      target_height: None,
+      error: None,
+    })).collect();
     DaemonClient {
       config,
+      daemons,
+      active: AtomicUsize::new(0),
+    }
+  }
+
+  /// Checks every daemon's height and sync status, and switches to the best healthy one.
+  pub fn check_health(&self) {
+    let urls: Vec<String> = self.daemons.iter()
+      .map(|daemon| daemon.read().unwrap().url.to_owned())
+      .collect();
+    let infos: Vec<StdResult<NodeInfo, String>> = urls.iter().map(|url| get_info(url)).collect();
+    let healthy = assess_health(&infos.iter().map(|info| info.as_ref().ok().cloned())
+      .collect::<Vec<_>>());
+    for (i, info) in infos.into_iter().enumerate() {
+      let mut daemon = self.daemons[i].write().unwrap();
+      if daemon.healthy && !healthy[i] {
+        warn!("Daemon {} is unhealthy: {:?}", daemon.url, info);
+      } else if !daemon.healthy && healthy[i] {
+        info!("Daemon {} is healthy again", daemon.url);
+      }
+      daemon.healthy = healthy[i];
+      match info {
+        Ok(info) => {
+          daemon.height = Some(info.height);
+          daemon.target_height = info.target_height;
+          daemon.error = if !info.synchronized {
+            Some("Not synchronized".to_owned())
+          } else if !healthy[i] {
+            Some("Behind the other daemons".to_owned())
+          } else {
+            None
+          };
+        },
+        Err(err) => daemon.error = Some(err),
+      }
+    }
+    if let Some(best) = healthy.iter().position(|healthy| *healthy) {
+      self.switch_to(best);
+    }
+  }
+
+  /// The state of each daemon, as of the last health check.
+  pub fn statuses(&self) -> Vec<DaemonStatus> {
+    self.daemons.iter().map(|daemon| daemon.read().unwrap().clone()).collect()
+  }
+
+  /// Explains why the active daemon can't be trusted to be on the network's tip, if it can't.
+  pub fn sync_problem(&self) -> Option<String> {
+    sync_problem(&self.statuses(), self.active.load(Ordering::SeqCst))
+  }
+
+  fn switch_to(&self, index: usize) {
+    let previous = self.active.swap(index, Ordering::SeqCst);
+    if previous != index {
+      warn!("Failing over from daemon {} to {}", self.daemons[previous].read().unwrap().url,
+            self.daemons[index].read().unwrap().url);
+    }
+  }
+
+  /// Calls the active daemon, and if it can't be reached, the other daemons in failover_order.
+  /// Errors that a daemon answers with are returned straight away, since they come from the call
+  /// itself rather than the daemon.
+  fn call_with_failover(&self, method: &str, params: Value) -> StdResult<Value, String> {
+    self.call_with_failover_from(method, params).map(|(_, value)| value)
+  }
+
+  /// Like call_with_failover, but also returns the URL of the daemon that answered.
+  fn call_with_failover_from(&self, method: &str, params: Value)
+                             -> StdResult<(String, Value), String> {
+    let healthy: Vec<bool> = self.daemons.iter()
+      .map(|daemon| daemon.read().unwrap().healthy)
+      .collect();
+    let mut last_error = "No daemons configured".to_owned();
+    for i in failover_order(self.active.load(Ordering::SeqCst), &healthy) {
+      let url = self.daemons[i].read().unwrap().url.to_owned();
+      match call_daemon(&url, method, params.clone()) {
+        Ok(value) => {
+          self.switch_to(i);
+          return Ok((url, value));
+        },
+        Err(DaemonError::Rpc(err)) => return Err(err),
+        Err(DaemonError::Transport(err)) => {
+          let mut daemon = self.daemons[i].write().unwrap();
+          daemon.healthy = false;
+          daemon.error = Some(err.to_owned());
+          last_error = err;
+        },
+      }
     }
+    Err(last_error)
   }
 
-  pub fn submit_block(&self, block: &str) -> Result<Value, String> {
-    self.call_daemon(&self.config.daemon_url, "submitblock", json!([block]))
+  /// Submits a found block to every healthy daemon at once, so that it spreads through the network
+  /// from several places.  This returns as soon as one of the daemons accepts the block, so that a
+  /// slow daemon doesn't hold up the miner's response, and fails if none of them do.
+  pub fn submit_block(&self, block: &str) -> StdResult<Value, String> {
+    let urls: Vec<String> = self.daemons.iter()
+      .map(|daemon| daemon.read().unwrap())
+      .filter(|daemon| daemon.healthy)
+      .map(|daemon| daemon.url.to_owned())
+      .collect();
+    if urls.is_empty() {
+      return self.call_with_failover("submitblock", json!([block]));
+    }
+    let (sender, results) = mpsc::channel();
+    for url in urls {
+      let block = block.to_owned();
+      let sender = sender.clone();
+      thread::spawn(move || {
+        let result = call_daemon(&url, "submitblock", json!([block]));
+        if let Err(ref err) = result {
+          warn!("Failed to submit block: {}", err);
+        }
+        // Nobody is listening any more once another daemon has accepted the block.
+        let _ = sender.send(result);
+      });
+    }
+    // Leaves the submission threads with the only senders, so that we stop waiting once they've all
+    // finished.
+    drop(sender);
+    let mut last_error = "Block submission thread panicked".to_owned();
+    for result in results.iter() {
+      match result {
+        Ok(value) => return Ok(value),
+        Err(err) => last_error = err.to_string(),
+      }
+    }
+    Err(last_error)
   }
 
-  pub fn get_block_template(&self) -> Result<Value, String> {
-    self.call_daemon(&self.config.daemon_url, "getblocktemplate", json!({
+  /// Gets a block template, along with the URL of the daemon that it came from.
+  pub fn get_block_template(&self) -> StdResult<(String, Value), String> {
+    self.call_with_failover_from("getblocktemplate", json!({
       "wallet_address": self.config.pool_wallet,
-      "reserve_size": 8
+      "reserve_size": RESERVE_SIZE,
     }))
   }
 
-  pub fn get_block_header(&self, hash: &String) -> Result<BlockHeader, String> {
-    match self.call_daemon(&self.config.daemon_url, "getblockheaderbyhash", json!({"hash": hash})) {
+  pub fn get_block_header(&self, hash: &String) -> StdResult<BlockHeader, String> {
+    match self.call_with_failover("getblockheaderbyhash", json!({"hash": hash})) {
       Ok(value) => {
         let bad_header_response = "Bad header response from daemon";
         let block_header = value.as_object()
@@ -67,8 +295,8 @@ impl DaemonClient {
     }
   }
 
-  pub fn transfer(&self, transfers: &[Transfer]) -> Result<TransferResult, String> {
-    match self.call_daemon(&self.config.wallet_url, "transfer", json!({
+  pub fn transfer(&self, transfers: &[Transfer]) -> StdResult<TransferResult, String> {
+    match call_daemon(&self.config.wallet_url, "transfer", json!({
       "destinations": transfers,
       // The fee is specified, in the wallet API, but ignored by many coins
       "fee": self.config.network_transaction_fee,
@@ -86,33 +314,114 @@ impl DaemonClient {
         serde_json::from_value(transfer_result)
           .map_err(|_| error_msg.to_owned())
       },
-      Err(err) => Err(err),
+      Err(err) => Err(err.to_string()),
     }
   }
 
-  fn call_daemon(&self, url: &str, method: &str, params: Value) -> Result<Value, String> {
-    let map = json!({
-      "jsonrpc": Value::String("2.0".to_owned()),
-      "id": Value::String("0".to_owned()),
-      "method": Value::String(method.to_owned()),
-      "params": params,
-    });
-    let client = reqwest::Client::new();
-    let mut res = client.post(url)
-      .json(&map)
-      .send().map_err(|err| format!("Bad response from RPC server: {:?}", err))?;
-    let json = res.json().map_err(|_| "Invalid JSON from RPC server".to_owned());
-    match json {
-      Ok(Value::Object(map)) => {
-        if let Some(&Value::Object(ref err_object)) = map.get("error") {
-          return Err(format!("Daemon produced error '{}', during {} on {}", match err_object.get("message") {
-            Some(&Value::String(ref err_message)) => err_message.to_owned(),
-            other => format!("{:?}", other)
-          }, method, url));
-        }
-        Ok(Value::Object(map))
+}
+
+fn get_info(url: &str) -> StdResult<NodeInfo, String> {
+  let response = call_daemon(url, "get_info", json!({})).map_err(|err| err.to_string())?;
+  let info = response.get("result").ok_or(format!("Bad get_info response from {}", url))?;
+  let height = info.get("height").and_then(|height| height.as_u64())
+    .ok_or(format!("No height in get_info response from {}", url))?;
+  // Older daemons don't report whether they're synchronized, but do report how far the network
+  // has got.
+  // Monero reports a target height of 0 until it has heard from its peers.
+  let target_height = info.get("target_height")
+    .and_then(|target_height| target_height.as_u64())
+    .and_then(|target_height| if target_height > 0 { Some(target_height) } else { None });
+  let synchronized = match info.get("synchronized") {
+    Some(&Value::Bool(synchronized)) => synchronized,
+    _ => target_height.map_or(true, |target_height| target_height <= height),
+  };
+  Ok(NodeInfo {
+    height,
+    target_height,
+    synchronized,
+  })
+}
+
+fn call_daemon(url: &str, method: &str, params: Value) -> StdResult<Value, DaemonError> {
+  let map = json!({
+    "jsonrpc": Value::String("2.0".to_owned()),
+    "id": Value::String("0".to_owned()),
+    "method": Value::String(method.to_owned()),
+    "params": params,
+  });
+  let client = reqwest::Client::new();
+  let mut res = client.post(url)
+    .json(&map)
+    .send()
+    .map_err(|err| DaemonError::Transport(format!("Bad response from RPC server: {:?}", err)))?;
+  let json = res.json()
+    .map_err(|_| DaemonError::Transport("Invalid JSON from RPC server".to_owned()));
+  match json {
+    Ok(Value::Object(map)) => {
+      if let Some(&Value::Object(ref err_object)) = map.get("error") {
+        return Err(DaemonError::Rpc(format!("Daemon produced error '{}', during {} on {}", match err_object.get("message") {
+          Some(&Value::String(ref err_message)) => err_message.to_owned(),
+          other => format!("{:?}", other)
+        }, method, url)));
       }
-      other => other,
+      Ok(Value::Object(map))
+    }
+    other => other,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use daemon_client::*;
+
+  fn info(height: u64, synchronized: bool) -> Option<NodeInfo> {
+    Some(NodeInfo {
+      height,
+      target_height: None,
+      synchronized,
+    })
+  }
+
+  fn status(healthy: bool, height: u64, target_height: Option<u64>) -> DaemonStatus {
+    DaemonStatus {
+      url: "http://localhost:18081/json_rpc".to_owned(),
+      priority: 0,
+      healthy,
+      height: Some(height),
+      target_height,
+      error: None,
     }
   }
-}
\ No newline at end of file
+
+  #[test]
+  fn test_assess_health() {
+    assert_eq!(assess_health(&[info(1000, true), info(1000, true)]), vec![true, true]);
+    // One block behind is normal while a new block propagates, but any further means the daemon is
+    // stuck or on its own fork.
+    assert_eq!(assess_health(&[info(999, true), info(1000, true)]), vec![true, true]);
+    assert_eq!(assess_health(&[info(998, true), info(1000, true)]), vec![false, true]);
+    assert_eq!(assess_health(&[None, info(1000, false), info(1000, true)]),
+               vec![false, false, true]);
+    assert_eq!(assess_health(&[None]), vec![false]);
+  }
+
+  #[test]
+  fn test_failover_order() {
+    assert_eq!(failover_order(0, &[true, true, true]), vec![0, 1, 2]);
+    assert_eq!(failover_order(1, &[true, true, true]), vec![1, 0, 2]);
+    // The active daemon is still tried first after a failed health check, but unhealthy daemons go
+    // after all of the healthy ones.
+    assert_eq!(failover_order(0, &[false, false, true]), vec![0, 2, 1]);
+    assert_eq!(failover_order(2, &[true, false, true, false]), vec![2, 0, 1, 3]);
+    assert_eq!(failover_order(0, &[false]), vec![0]);
+  }
+
+  #[test]
+  fn test_sync_problem() {
+    assert_eq!(sync_problem(&[status(true, 1000, None)], 0), None);
+    assert_eq!(sync_problem(&[status(true, 1000, Some(1001))], 0), None);
+    assert!(sync_problem(&[status(true, 1000, Some(1050))], 0).is_some());
+    assert!(sync_problem(&[status(false, 1000, None), status(false, 1000, None)], 0).is_some());
+    assert_eq!(sync_problem(&[status(false, 900, None), status(true, 1000, None)], 1), None);
+  }
+}
@@ -1,4 +1,6 @@
+use bans::BanTarget;
 use blocktemplate::*;
+use chrono::NaiveDateTime;
 use daemon_client::Transfer;
 use miner::*;
 use diesel::prelude::*;
@@ -38,6 +40,19 @@ impl DbAccess {
     }
   }
 
+  /// A database that never connects, for tests of code that carries on without one.
+  #[cfg(test)]
+  pub fn unconnected() -> DbAccess {
+    let manager = ConnectionManager::<PgConnection>::new("postgres://127.0.0.1:1/cryptosmelt");
+    let pool = Pool::builder()
+      .min_idle(Some(0))
+      .connection_timeout(::std::time::Duration::from_millis(10))
+      .build_unchecked(manager);
+    DbAccess {
+      conn_pool: pool,
+    }
+  }
+
   pub fn is_connected(&self) -> bool {
     self.conn_pool.get().is_ok()
   }
@@ -72,6 +87,8 @@ impl DbAccess {
       address: &miner.address,
       miner_alias: &alias,
       shares: job.difficulty as i64,
+      agent: miner.agent.as_ref().map(|agent| agent.as_str()),
+      rig_id: miner.rig_id.as_ref().map(|rig_id| rig_id.as_str()),
     };
 
     if let Ok(conn) = self.conn_pool.get() {
@@ -207,6 +224,28 @@ impl DbAccess {
     }
   }
 
+  /// Totals the shares submitted in the last hour for each mining software agent string.
+  pub fn shares_by_agent(&self) -> Vec<AgentShares> {
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = diesel::sql_query(
+        "SELECT agent, COUNT(DISTINCT (address, miner_alias)) AS miners, \
+         CAST(SUM(shares) AS BIGINT) AS shares \
+         FROM valid_share WHERE created > now() - interval '1 hour' \
+         GROUP BY agent"
+      ).load(&*conn);
+      match result {
+        Ok(shares) => shares,
+        Err(err) => {
+          warn!("Failed to get shares by agent: {:?}", err);
+          vec![]
+        },
+      }
+    }
+    else {
+      vec![]
+    }
+  }
+
   pub fn hashrates_by_address(&self, address_pattern: &Regex, address: &str) -> Vec<MinerStats> {
     if !address_pattern.is_match(address) {
       // Checking against the address pattern is important - we're not using diesel's query builder
@@ -355,4 +394,104 @@ impl DbAccess {
       vec![]
     }
   }
+
+  pub fn active_bans(&self, now: NaiveDateTime) -> Vec<MinerBan> {
+    use db::schema::miner_ban::dsl;
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = dsl::miner_ban.filter(dsl::expires.is_null().or(dsl::expires.gt(now)))
+        .load(&*conn);
+      match result {
+        Ok(bans) => bans,
+        Err(err) => {
+          warn!("Failed to get bans: {:?}", err);
+          vec![]
+        },
+      }
+    }
+    else {
+      vec![]
+    }
+  }
+
+  /// Saves a ban, replacing any earlier ban on the same IP or address.
+  pub fn add_ban(&self, ban: &NewMinerBan) -> Option<MinerBan> {
+    use db::schema::miner_ban::dsl;
+    if let Ok(conn) = self.conn_pool.get() {
+      let changes = (
+        dsl::created.eq(diesel::dsl::now),
+        dsl::reason.eq(ban.reason),
+        dsl::expires.eq(ban.expires),
+        dsl::source.eq(ban.source),
+      );
+      let result = if ban.ip.is_some() {
+        diesel::insert_into(miner_ban::table)
+          .values(ban)
+          .on_conflict(dsl::ip)
+          .do_update()
+          .set(changes)
+          .get_result::<MinerBan>(&*conn)
+      } else {
+        diesel::insert_into(miner_ban::table)
+          .values(ban)
+          .on_conflict(dsl::address)
+          .do_update()
+          .set(changes)
+          .get_result::<MinerBan>(&*conn)
+      };
+      match result {
+        Ok(ban) => Some(ban),
+        Err(err) => {
+          warn!("Failed saving ban, error: {:?}", err);
+          None
+        },
+      }
+    }
+    else {
+      warn!("No available database connection.");
+      None
+    }
+  }
+
+  /// Deletes the bans that expired before the given time, returning how many there were.
+  pub fn delete_expired_bans(&self, now: NaiveDateTime) -> usize {
+    use db::schema::miner_ban::dsl;
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = diesel::delete(dsl::miner_ban.filter(dsl::expires.le(now)))
+        .execute(&*conn);
+      match result {
+        Ok(count) => count,
+        Err(err) => {
+          warn!("Failed deleting expired bans, error: {:?}", err);
+          0
+        },
+      }
+    }
+    else {
+      warn!("No available database connection.");
+      0
+    }
+  }
+
+  pub fn lift_bans(&self, target: &BanTarget) -> usize {
+    use db::schema::miner_ban::dsl;
+    if let Ok(conn) = self.conn_pool.get() {
+      let result = match target {
+        &BanTarget::Ip(ref ip) => diesel::delete(dsl::miner_ban.filter(dsl::ip.eq(ip.to_string())))
+          .execute(&*conn),
+        &BanTarget::Address(ref address) => diesel::delete(dsl::miner_ban.filter(dsl::address.eq(address)))
+          .execute(&*conn),
+      };
+      match result {
+        Ok(count) => count,
+        Err(err) => {
+          warn!("Failed lifting bans, error: {:?}", err);
+          0
+        },
+      }
+    }
+    else {
+      warn!("No available database connection.");
+      0
+    }
+  }
 }
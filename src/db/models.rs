@@ -70,6 +70,45 @@ pub struct NewMinerBalance<'a> {
   pub is_fee: bool,
 }
 
+pub enum BanSource {
+  Automatic, Manual
+}
+impl Into<i32> for BanSource {
+  fn into(self) -> i32 {
+    match self {
+      BanSource::Automatic => 0,
+      BanSource::Manual => 1,
+    }
+  }
+}
+impl From<i32> for BanSource {
+  fn from(i: i32) -> BanSource {
+    match i {
+      0 => BanSource::Automatic,
+      _ => BanSource::Manual,
+    }
+  }
+}
+#[derive(Queryable, Serialize, Clone, Debug)]
+pub struct MinerBan {
+  pub id: i32,
+  pub created: NaiveDateTime,
+  pub ip: Option<String>,
+  pub address: Option<String>,
+  pub reason: String,
+  pub expires: Option<NaiveDateTime>,
+  pub source: i32,
+}
+#[derive(Insertable)]
+#[table_name="miner_ban"]
+pub struct NewMinerBan<'a> {
+  pub ip: Option<&'a str>,
+  pub address: Option<&'a str>,
+  pub reason: &'a str,
+  pub expires: Option<NaiveDateTime>,
+  pub source: i32,
+}
+
 #[derive(Queryable)]
 pub struct PoolPayment {
   pub id: i32,
@@ -91,6 +130,8 @@ pub struct ValidShare {
   pub address: String,
   pub miner_alias: String,
   pub shares: i64,
+  pub agent: Option<String>,
+  pub rig_id: Option<String>,
 }
 #[derive(Insertable)]
 #[table_name="valid_share"]
@@ -98,6 +139,8 @@ pub struct NewShare<'a> {
   pub address: &'a str,
   pub miner_alias: &'a str,
   pub shares: i64,
+  pub agent: Option<&'a str>,
+  pub rig_id: Option<&'a str>,
 }
 
 #[derive(QueryableByName, Serialize)]
@@ -135,4 +178,19 @@ pub struct MinerBalanceTotal {
   #[sql_type="Varchar"]
   #[column_name="address"]
   pub address: String,
-}
\ No newline at end of file
+}
+
+#[derive(QueryableByName, Serialize, Debug)]
+pub struct AgentShares {
+  #[sql_type="Nullable<Varchar>"]
+  #[column_name="agent"]
+  pub agent: Option<String>,
+
+  #[sql_type="Int8"]
+  #[column_name="miners"]
+  pub miners: i64,
+
+  #[sql_type="Int8"]
+  #[column_name="shares"]
+  pub shares: i64,
+}
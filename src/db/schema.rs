@@ -16,6 +16,18 @@ table! {
     }
 }
 
+table! {
+    miner_ban (id) {
+        id -> Int4,
+        created -> Timestamp,
+        ip -> Nullable<Varchar>,
+        address -> Nullable<Varchar>,
+        reason -> Text,
+        expires -> Nullable<Timestamp>,
+        source -> Int4,
+    }
+}
+
 table! {
     miner_balance (id) {
         id -> Int4,
@@ -43,6 +55,8 @@ table! {
         address -> Varchar,
         miner_alias -> Varchar,
         shares -> Int8,
+        agent -> Nullable<Varchar>,
+        rig_id -> Nullable<Varchar>,
     }
 }
 
@@ -51,6 +65,7 @@ joinable!(block_progress -> found_block (block_id));
 allow_tables_to_appear_in_same_query!(
     block_progress,
     found_block,
+    miner_ban,
     miner_balance,
     pool_payment,
     valid_share,
@@ -15,12 +15,10 @@ extern crate toml;
 extern crate rocket;
 extern crate rocket_contrib;
 extern crate jsonrpc_core;
-extern crate jsonrpc_tcp_server;
 extern crate lru_time_cache;
 extern crate concurrent_hashmap;
 extern crate uuid;
 extern crate reqwest;
-extern crate schedule_recv;
 extern crate bytes;
 extern crate num_bigint;
 extern crate num_integer;
@@ -30,25 +28,35 @@ extern crate blake;
 extern crate jhffi;
 extern crate skeinffi;
 extern crate regex;
+extern crate openssl;
+extern crate ws;
 #[macro_use]
 extern crate diesel;
 extern crate dotenv;
 extern crate r2d2;
 extern crate r2d2_diesel;
+#[cfg(feature = "zmq")]
+extern crate zmq;
 
 
 mod api;
 mod app;
+mod bans;
 mod blocktemplate;
 mod config;
 mod crypto;
+mod cryptonote;
 mod daemon_client;
 mod db;
 mod miner;
+mod notify;
 mod stratum;
 mod unlocker;
+mod vardiff;
 
 use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use app::App;
 
 fn main() {
@@ -68,6 +76,16 @@ fn main() {
     .chain(fern::log_file(&config.log_file).expect("Invalid log file"))
     .apply().unwrap();
   let app_ref = Arc::new(App::new(config));
-  api::init(app_ref.clone());
-  stratum::init(app_ref);
+  if let Some(ref zmq_url) = app_ref.config.zmq_url {
+    notify::subscribe(zmq_url.to_owned(), app_ref.block_notifier.clone());
+  }
+  // Health checks run on their own, since a daemon that's gone away can take a while to time out.
+  let health_app = app_ref.clone();
+  thread::spawn(move || loop {
+    health_app.daemon.check_health();
+    thread::sleep(Duration::from_secs(daemon_client::HEALTH_CHECK_INTERVAL));
+  });
+  let stratum = stratum::init(app_ref.clone());
+  api::init(app_ref.clone(), stratum.clone());
+  stratum::run(app_ref, stratum);
 }
@@ -1,93 +1,200 @@
+use std::mem;
 use std::sync::{Arc, Mutex};
 use config::*;
 use uuid::*;
 use jsonrpc_core::*;
 use jsonrpc_core::futures::sync::mpsc::*;
-use jsonrpc_core::futures::*;
 use std::net::SocketAddr;
-use std::time::*;
 use std::sync::atomic::*;
 use lru_time_cache::*;
 use blocktemplate::*;
+use vardiff::*;
+
+/// The connection that a miner's session runs over.
+pub struct MinerConnection {
+  pub peer_addr: SocketAddr,
+  /// Jobs sent here get pushed to the miner.
+  pub sender: Sender<String>,
+  /// Setting this asks the transport to close the connection.
+  pub closed: Arc<AtomicBool>,
+}
+
+/// What we remember about a worker after its connection ends, so that it can pick up where it left
+/// off when it reconnects.
+pub struct SessionState {
+  pub difficulty: u64,
+  /// Holds the recent shares that the hashrate estimate is based on.
+  pub vardiff: VarDiff,
+  pub trust: usize,
+}
 
 pub struct Miner {
   pub id: String,
   pub address: String,
   pub alias: Option<String>,
+  /// The mining software, as announced in the login agent string.
+  pub agent: Option<String>,
+  pub rig_id: Option<String>,
   pub password: String,
-  pub peer_addr: SocketAddr,
-  pub connection: Sender<String>,
+  pub connection: MinerConnection,
   pub difficulty: AtomicUsize,
-  pub jobs: Mutex<LruCache<String, Job>>,
-  pub session_shares: AtomicUsize,
-  pub session_start: SystemTime,
+  /// Set when the miner picked its own difficulty at login, which turns off vardiff.
+  pub fixed_difficulty: bool,
+  pub jobs: Mutex<LruCache<String, Arc<Job>>>,
+  pub vardiff: Mutex<VarDiff>,
+  /// The number of valid shares from this worker, including those from sessions it resumed.  Only
+  /// workers that have submitted valid shares get their difficulty back when they reconnect.
+  pub trust: AtomicUsize,
+  /// Set on NiceHash ports, where the marketplace picks the top nonce byte for each of its miners.
+  pub nicehash: bool,
+  /// Proxies get the whole block template instead of a hashing blob, so that they can fill in part
+  /// of the extra nonce for each of their own miners.
+  pub proxy: bool,
 }
 
 impl Miner {
-  pub fn new(address: &str, alias: Option<String>, peer_addr: SocketAddr,
-             connection: Sender<String>, difficulty: usize) -> Miner {
+  pub fn new(address: &str, alias: Option<String>, agent: Option<String>, rig_id: Option<String>,
+             connection: MinerConnection, difficulty: usize, fixed_difficulty: bool,
+             nicehash: bool, proxy: bool) -> Miner {
     let id = &Uuid::new_v4().to_string();
     Miner {
       id: id.to_owned(),
       address: address.to_owned(),
       alias,
+      agent,
+      rig_id,
       password: "".to_owned(),
-      peer_addr,
       connection,
       difficulty: AtomicUsize::new(difficulty),
+      fixed_difficulty,
       jobs: Mutex::new(LruCache::with_capacity(3)),
-      session_shares: AtomicUsize::new(0),
-      session_start: SystemTime::now(),
+      vardiff: Mutex::new(VarDiff::new()),
+      trust: AtomicUsize::new(0),
+      nicehash,
+      proxy,
     }
   }
 
   pub fn get_job(&self, job_provider: &Arc<JobProvider>) -> Result<Value> {
     // Notes on the block template:
-    // - reserve_size (8) is the amount of bytes to reserve so the pool can throw in an extra nonce
+    // - reserve_size (16) is the amount of bytes to reserve so the pool can throw in an extra nonce
     // - the daemon returns result.reserved_offset, and that many bytes into
     //   result.blocktemplate_blob, we can write our 8 byte extra nonce
     // - the node pools use a global counter, but we might want the counter to be per-miner
     // - it might not even be necessary to use any counters
     //   (and just go with the first 8 bytes of the miner id)
-    if let Some(new_job) = job_provider.get_job(self.difficulty.load(Ordering::Relaxed) as u64) {
-      let response = Ok(json!({
+    if job_provider.is_frozen() {
+      return Err(Error {
+        code: ErrorCode::ServerError(-1),
+        message: "Pool is waiting for its daemon to catch up with the network".to_owned(),
+        data: None,
+      });
+    }
+    let difficulty = self.difficulty.load(Ordering::Relaxed) as u64;
+    if self.proxy {
+      return self.get_proxy_job(job_provider, difficulty);
+    }
+    if let Some(new_job) = job_provider.get_job(difficulty) {
+      let mut job_json = json!({
         "job_id": new_job.id,
         "blob": new_job.hashing_blob,
         "target": new_job.diff_hex,
+        "algo": new_job.algo(),
+      });
+      if self.nicehash {
+        // Tells miners to keep the top byte of the nonce as NiceHash set it, and only search
+        // through the lower three.
+        job_json["nicehash"] = Value::Bool(true);
+      }
+      let response = Ok(job_json);
+      self.jobs.lock().unwrap().insert(new_job.id.to_owned(), Arc::new(new_job));
+      return response;
+    }
+    Err(Error::internal_error())
+  }
+
+  fn get_proxy_job(&self, job_provider: &Arc<JobProvider>, difficulty: u64) -> Result<Value> {
+    if let Some(new_job) = job_provider.get_proxy_job(difficulty) {
+      // This is the job format of node-cryptonote-pool's descendants, which xmr-node-proxy expects.
+      // Offsets are in bytes, into blocktemplate_blob.  Our extra nonce takes the first 8 bytes of
+      // the reserved space, and the proxy writes 4 byte nonces for its worker threads and their
+      // miners into the other 8.
+      let reserved_offset = new_job.reserved_offset();
+      let response = Ok(json!({
+        "id": self.id,
+        "job_id": new_job.id,
+        "blocktemplate_blob": new_job.template_blob_with_extra_nonce(),
+        "difficulty": new_job.network_difficulty,
+        "height": new_job.height,
+        "reserved_offset": reserved_offset,
+        "client_pool_offset": reserved_offset + 8,
+        "client_nonce_offset": reserved_offset + 12,
+        "target_diff": new_job.difficulty,
+        "target_diff_hex": new_job.diff_hex,
+        "algo": new_job.algo(),
       }));
-      self.jobs.lock().unwrap().insert(new_job.id.to_owned(), new_job);
+      self.jobs.lock().unwrap().insert(new_job.id.to_owned(), Arc::new(new_job));
       return response;
     }
     Err(Error::internal_error())
   }
 
-  pub fn adjust_difficulty(&self, new_shares: u64, config: &ServerConfig) {
-    let total_shares = self.session_shares.fetch_add(new_shares as usize, Ordering::SeqCst) as u64;
-    let secs_since_start = SystemTime::now().duration_since(self.session_start)
-      .expect("Session start is in the future, this shouldn't happen")
-      .as_secs();
-    let buffer_seconds = 60 * 5;
-    // These 'buffer' shares are not actually given to the miner, but just used as a smoothing
-    // factor so that the miner's hashrate doesn't jump all over the place within the first few
-    // minutes.  The number of buffer shares is equal to the number of shares a miner would get if
-    // they connected to a stratum port, and mined at the exact hashrate that port is tuned for,
-    // over a period of 5 minutes.
-    let buffer_shares = (config.starting_difficulty * buffer_seconds) / config.target_time;
-    // We then factor in those buffer shares to get a smoothed-out estimate of the miner's hashrate.
-    let miner_hashrate = (total_shares + buffer_shares) / (secs_since_start + buffer_seconds);
-    let ideal_difficulty = miner_hashrate * config.target_time;
-    let actual_difficulty = self.difficulty.load(Ordering::Relaxed) as f64;
-    let difficulty_ratio = (ideal_difficulty as f64) / actual_difficulty;
-    if (difficulty_ratio - 1.0).abs() > 0.25 {
-      debug!("Adjusting miner to difficulty {}, address {}", ideal_difficulty, self.address);
-      // Each time we get a new block template, the miners need new jobs anyways - so we just leave
-      // the retargeting to that process.  Calling retarget_job here would be slightly tricky since
-      // we don't want to interrupt an in-progress RPC call from the miner.
-      self.difficulty.store(ideal_difficulty as usize, Ordering::Relaxed);
+  /// Records the shares from an accepted submission, and returns true if the difficulty changed
+  /// enough that the miner should get a new job right away.  Smaller changes are left for the next
+  /// template refresh to send out.
+  pub fn adjust_difficulty(&self, new_shares: u64, config: &ServerConfig) -> bool {
+    if self.fixed_difficulty {
+      return false;
+    }
+    let current_difficulty = self.difficulty.load(Ordering::Relaxed) as u64;
+    let settings = VarDiffSettings::from(config);
+    match self.vardiff.lock().unwrap().record_share(new_shares, current_difficulty, &settings) {
+      Retarget::Unchanged => false,
+      Retarget::NextJob(new_difficulty) => {
+        debug!("Adjusting miner to difficulty {}, address {}", new_difficulty, self.address);
+        self.difficulty.store(new_difficulty as usize, Ordering::Relaxed);
+        false
+      },
+      Retarget::Immediate(new_difficulty) => {
+        debug!("Retargeting miner to difficulty {}, address {}", new_difficulty, self.address);
+        self.difficulty.store(new_difficulty as usize, Ordering::Relaxed);
+        true
+      },
+    }
+  }
+
+  /// Forgets the jobs sent to this miner, so that any shares for them are rejected as expired.
+  pub fn drop_jobs(&self) {
+    *self.jobs.lock().unwrap() = LruCache::with_capacity(3);
+  }
+
+  pub fn close(&self) {
+    self.connection.closed.store(true, Ordering::Relaxed);
+  }
+
+  /// Whether the connection has ended, either from our end or the miner's.
+  pub fn is_closed(&self) -> bool {
+    self.connection.closed.load(Ordering::Relaxed)
+  }
+
+  /// Takes the state that a reconnecting session can resume from, leaving this miner with an empty
+  /// vardiff history.
+  pub fn take_session_state(&self) -> SessionState {
+    SessionState {
+      difficulty: self.difficulty.load(Ordering::Relaxed) as u64,
+      vardiff: mem::replace(&mut *self.vardiff.lock().unwrap(), VarDiff::new()),
+      trust: self.trust.load(Ordering::Relaxed),
     }
   }
 
-  pub fn retarget_job(&self, job_provider: &Arc<JobProvider>) {
+  pub fn resume(&self, state: SessionState) {
+    self.difficulty.store(state.difficulty as usize, Ordering::Relaxed);
+    *self.vardiff.lock().unwrap() = state.vardiff;
+    self.trust.store(state.trust, Ordering::Relaxed);
+  }
+
+  /// Pushes a new job to the miner, returning false if the connection has been closed.
+  pub fn retarget_job(&self, job_provider: &Arc<JobProvider>) -> bool {
     let miner_job = self.get_job(job_provider);
     if let Ok(miner_job) = miner_job {
       let job_to_send = serde_json::to_string(&json!({
@@ -95,15 +202,49 @@ impl Miner {
           "method": "job",
           "params": miner_job,
         }));
-      let connection = self.connection.clone();
+      let mut sender = self.connection.sender.clone();
       if let &Ok(ref job) = &job_to_send {
-        if let Err(err) = connection.send(job.to_owned()).poll() {
-          debug!("Non-JSONRPC response from miner after sending job, though this is normal: {:?}", err)
+        if let Err(err) = sender.try_send(job.to_owned()) {
+          if err.is_disconnected() {
+            debug!("Connection to {} is closed", &self.connection.peer_addr);
+            return false;
+          }
+          // A full channel means the miner isn't reading its jobs quickly, but is still connected.
+          debug!("Failed to send job to {}: {:?}", &self.connection.peer_addr, err);
         }
       }
       if let Err(err) = job_to_send {
-        debug!("Failed to write job to {}: {:?}", &self.peer_addr, err);
+        debug!("Failed to write job to {}: {:?}", &self.connection.peer_addr, err);
       }
     }
+    true
+  }
+}
+
+/// Splits an agent string like "XMRig/2.6.2 (Linux x86_64) libuv/1.8.0" into the software name and
+/// version.
+pub fn parse_agent(agent: &str) -> (&str, Option<&str>) {
+  let software = agent.split_whitespace().next().unwrap_or("");
+  let mut parts = software.splitn(3, '/');
+  let name = parts.next().unwrap_or("");
+  match parts.next() {
+    Some(version) if !version.is_empty() => (name, Some(version)),
+    _ => (name, None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use miner::*;
+
+  #[test]
+  fn test_parse_agent() {
+    assert_eq!(parse_agent("XMRig/2.6.2 (Linux x86_64) libuv/1.8.0 gcc/5.4.0"),
+               ("XMRig", Some("2.6.2")));
+    assert_eq!(parse_agent("xmr-stak/2.4.3/c5f0505/master/lin/cpu/monero/20"),
+               ("xmr-stak", Some("2.4.3")));
+    assert_eq!(parse_agent("xmr-node-proxy/0.0.3"), ("xmr-node-proxy", Some("0.0.3")));
+    assert_eq!(parse_agent("minerd"), ("minerd", None));
+    assert_eq!(parse_agent(""), ("", None));
   }
 }
@@ -0,0 +1,96 @@
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// Wakes up the main loop when the daemon tells us about a new block, so that miners don't keep
+/// working on the old one until the next poll.
+pub struct BlockNotifier {
+  pending: Mutex<bool>,
+  condvar: Condvar,
+}
+
+impl BlockNotifier {
+  pub fn new() -> BlockNotifier {
+    BlockNotifier {
+      pending: Mutex::new(false),
+      condvar: Condvar::new(),
+    }
+  }
+
+  pub fn notify(&self) {
+    *self.pending.lock().unwrap() = true;
+    self.condvar.notify_all();
+  }
+
+  /// Waits until either a block notification arrives, or the timeout passes.  Returns true if there
+  /// was a notification, including one that came in since the last wait.
+  pub fn wait(&self, timeout: Duration) -> bool {
+    let mut pending = self.pending.lock().unwrap();
+    if !*pending {
+      pending = self.condvar.wait_timeout(pending, timeout).unwrap().0;
+    }
+    let notified = *pending;
+    *pending = false;
+    notified
+  }
+}
+
+/// Subscribes to the daemon's ZMQ publisher, which monerod runs with --zmq-pub, and notifies on
+/// every new block in the main chain.
+#[cfg(feature = "zmq")]
+pub fn subscribe(url: String, notifier: ::std::sync::Arc<BlockNotifier>) {
+  use std::thread;
+  use zmq;
+
+  const CHAIN_TOPIC: &[u8] = b"json-minimal-chain_main";
+
+  fn listen(context: &zmq::Context, url: &str, notifier: &BlockNotifier) -> zmq::Result<()> {
+    let socket = context.socket(zmq::SUB)?;
+    socket.connect(url)?;
+    socket.set_subscribe(CHAIN_TOPIC)?;
+    info!("Subscribed to block notifications from {}", url);
+    loop {
+      socket.recv_bytes(0)?;
+      notifier.notify();
+    }
+  }
+
+  thread::spawn(move || {
+    let context = zmq::Context::new();
+    loop {
+      if let Err(err) = listen(&context, &url, &notifier) {
+        warn!("Lost the ZMQ subscription to {}: {}", url, err);
+      }
+      thread::sleep(Duration::from_secs(5));
+    }
+  });
+}
+
+#[cfg(not(feature = "zmq"))]
+pub fn subscribe(url: String, _notifier: ::std::sync::Arc<BlockNotifier>) {
+  warn!("Not subscribing to {}, since this build doesn't have the zmq feature", url);
+}
+
+#[cfg(test)]
+mod tests {
+  use notify::*;
+  use std::sync::Arc;
+  use std::thread;
+
+  #[test]
+  fn test_block_notifier() {
+    let notifier = Arc::new(BlockNotifier::new());
+    assert!(!notifier.wait(Duration::from_millis(10)));
+    // Notifications that arrive while the main loop is busy aren't lost.
+    notifier.notify();
+    assert!(notifier.wait(Duration::from_millis(10)));
+    assert!(!notifier.wait(Duration::from_millis(10)));
+
+    let waker = notifier.clone();
+    let handle = thread::spawn(move || {
+      thread::sleep(Duration::from_millis(20));
+      waker.notify();
+    });
+    assert!(notifier.wait(Duration::from_secs(10)));
+    handle.join().unwrap();
+  }
+}
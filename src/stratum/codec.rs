@@ -0,0 +1,138 @@
+use serde_json;
+use serde_json::Value;
+
+/// The longest request we accept.  Real stratum requests are a few hundred bytes, so anything this
+/// long is someone trying to make us buffer garbage.
+pub const MAX_LINE_LENGTH: usize = 4096;
+/// How many malformed requests a connection can send before we drop it.
+pub const MAX_MALFORMED_REQUESTS: usize = 10;
+
+#[derive(Debug, PartialEq)]
+pub enum CodecError {
+  LineTooLong,
+}
+
+/// Splits the bytes read from a connection into newline-delimited requests, without ever holding
+/// more than one maximum-length line.
+pub struct LineCodec {
+  buffer: Vec<u8>,
+  max_line_length: usize,
+}
+
+impl LineCodec {
+  pub fn new(max_line_length: usize) -> LineCodec {
+    LineCodec {
+      buffer: Vec::new(),
+      max_line_length,
+    }
+  }
+
+  pub fn feed(&mut self, bytes: &[u8]) {
+    self.buffer.extend_from_slice(bytes);
+  }
+
+  /// Returns the next complete line, skipping blank ones, or None if we need to read more.
+  pub fn next_line(&mut self) -> Result<Option<String>, CodecError> {
+    loop {
+      let line_end = match self.buffer.iter().position(|byte| *byte == b'\n') {
+        Some(line_end) => line_end,
+        None if self.buffer.len() > self.max_line_length => return Err(CodecError::LineTooLong),
+        None => return Ok(None),
+      };
+      if line_end > self.max_line_length {
+        return Err(CodecError::LineTooLong);
+      }
+      let line: Vec<u8> = self.buffer.drain(..(line_end + 1)).collect();
+      let line = String::from_utf8_lossy(&line).trim().to_owned();
+      if !line.is_empty() {
+        return Ok(Some(line));
+      }
+    }
+  }
+}
+
+/// Checks that a request has the shape of a stratum call before it reaches the JSON-RPC handler:
+/// an object with a short numeric or string id, a method name, and params as an object.
+pub fn validate_request(request: &str) -> Result<(), &'static str> {
+  let request: Value = serde_json::from_str(request).map_err(|_| "Request is not valid JSON")?;
+  let request = request.as_object().ok_or("Request must be a JSON object")?;
+  match request.get("id") {
+    Some(&Value::Number(_)) => {},
+    Some(&Value::String(ref id)) if id.len() <= 64 => {},
+    _ => return Err("Request id must be a number or a short string"),
+  }
+  match request.get("method") {
+    Some(&Value::String(_)) => {},
+    _ => return Err("Request method must be a string"),
+  }
+  match request.get("params") {
+    None | Some(&Value::Object(_)) => Ok(()),
+    _ => Err("Request params must be an object"),
+  }
+}
+
+fn is_lowercase_hex(value: &str) -> bool {
+  value.bytes().all(|byte| (byte >= b'0' && byte <= b'9') || (byte >= b'a' && byte <= b'f'))
+}
+
+/// Miner IDs and job IDs are both UUIDs in their hyphenated form.
+pub fn is_valid_uuid(id: &str) -> bool {
+  id.len() == 36 && id.char_indices().all(|(i, c)| match i {
+    8 | 13 | 18 | 23 => c == '-',
+    _ => c.is_ascii_hexdigit(),
+  })
+}
+
+/// Nonces are exactly 4 bytes of lowercase hex.
+pub fn is_valid_nonce(nonce: &str) -> bool {
+  nonce.len() == 8 && is_lowercase_hex(nonce)
+}
+
+#[cfg(test)]
+mod tests {
+  use stratum::codec::*;
+
+  #[test]
+  fn test_line_splitting() {
+    let mut codec = LineCodec::new(MAX_LINE_LENGTH);
+    codec.feed(b"{\"id\":1}\n\r\n{\"id\"");
+    assert_eq!(codec.next_line(), Ok(Some("{\"id\":1}".to_owned())));
+    assert_eq!(codec.next_line(), Ok(None));
+    codec.feed(b":2}\r\n");
+    assert_eq!(codec.next_line(), Ok(Some("{\"id\":2}".to_owned())));
+    assert_eq!(codec.next_line(), Ok(None));
+  }
+
+  #[test]
+  fn test_line_length_limit() {
+    let mut codec = LineCodec::new(16);
+    codec.feed(&[b'a'; 17]);
+    assert_eq!(codec.next_line(), Err(CodecError::LineTooLong));
+
+    let mut codec = LineCodec::new(16);
+    codec.feed(b"aaaaaaaaaaaaaaaaaaaa\n");
+    assert_eq!(codec.next_line(), Err(CodecError::LineTooLong));
+  }
+
+  #[test]
+  fn test_request_validation() {
+    assert!(validate_request("{\"id\":1,\"method\":\"login\",\"params\":{}}").is_ok());
+    assert!(validate_request("{\"id\":\"a\",\"method\":\"getjob\"}").is_ok());
+    assert!(validate_request("[1,2,3]").is_err());
+    assert!(validate_request("{\"method\":\"login\",\"params\":{}}").is_err());
+    assert!(validate_request("{\"id\":{},\"method\":\"login\",\"params\":{}}").is_err());
+    assert!(validate_request("{\"id\":1,\"method\":\"login\",\"params\":[]}").is_err());
+    assert!(validate_request("not json").is_err());
+  }
+
+  #[test]
+  fn test_field_validation() {
+    assert!(is_valid_nonce("1234abcd"));
+    assert!(!is_valid_nonce("zz1234abcd"));
+    assert!(!is_valid_nonce("1234ABCD"));
+    assert!(!is_valid_nonce("1234abc"));
+    assert!(is_valid_uuid("c8d1e5a4-5a2f-4d36-9c1e-2d6f3b7e9a10"));
+    assert!(!is_valid_uuid("c8d1e5a4-5a2f-4d36-9c1e-2d6f3b7e9a1"));
+    assert!(!is_valid_uuid("c8d1e5a4x5a2f-4d36-9c1e-2d6f3b7e9a10"));
+  }
+}
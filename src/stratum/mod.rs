@@ -0,0 +1,645 @@
+mod codec;
+mod proxy_protocol;
+mod stream;
+mod timeouts;
+#[cfg(test)]
+mod transcripts;
+mod tls;
+mod websocket;
+
+use jsonrpc_core::*;
+use jsonrpc_core::serde_json::{Map};
+use jsonrpc_core::futures::sync::mpsc::*;
+use std::net::{SocketAddr, IpAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::*;
+use std::cmp::{min, max};
+use std::time::{Duration, Instant};
+use lru_time_cache::*;
+use config::*;
+use blocktemplate::*;
+use unlocker::Unlocker;
+use app::App;
+use miner::{Miner, MinerConnection, SessionState};
+use bans::BanTarget;
+use db::models::{BanSource, MinerBan};
+use stratum::timeouts::{ConnectionTimeout, Timeouts};
+
+/// Seconds that we remember a worker's difficulty and hashrate after its connection ends.
+const SESSION_MEMORY: u64 = 60 * 10;
+
+/// Agents which get jobs in the block template format, see Miner::proxy.
+const PROXY_AGENTS: &[&str] = &["xmr-node-proxy"];
+
+#[derive(Default, Clone)]
+struct Meta {
+  peer_addr: Option<SocketAddr>,
+  sender: Option<Sender<String>>,
+  /// Shared with the transport, which closes the connection once this is set.
+  closed: Option<Arc<AtomicBool>>,
+  /// Counts the malformed requests on the connection, which gets dropped after too many.
+  malformed_requests: Option<Arc<AtomicUsize>>,
+  /// Every request restarts the connection's idle timeout.
+  timeout: Option<Arc<ConnectionTimeout>>,
+}
+impl Metadata for Meta {}
+
+impl Meta {
+  fn is_closed(&self) -> bool {
+    self.closed.as_ref().map_or(false, |closed| closed.load(Ordering::Relaxed))
+  }
+}
+
+/// Identifies a worker across reconnects.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SessionKey {
+  address: String,
+  worker: Option<String>,
+  ip: IpAddr,
+}
+
+impl SessionKey {
+  fn of(miner: &Miner) -> SessionKey {
+    SessionKey {
+      address: miner.address.to_owned(),
+      worker: miner.alias.to_owned(),
+      ip: miner.connection.peer_addr.ip(),
+    }
+  }
+}
+
+struct StratumServer {
+  config: ServerConfig,
+  app: Arc<App>,
+  miner_connections: Mutex<LruCache<String, Arc<Miner>>>,
+  /// Recently ended sessions, which a reconnecting worker resumes from.
+  sessions: Mutex<LruCache<SessionKey, SessionState>>,
+  job_provider: Arc<JobProvider>,
+  /// The fingerprint of the port's TLS certificate, worked out once when the port starts.
+  tls_fingerprint: Option<String>,
+}
+
+impl StratumServer {
+  fn new(app: Arc<App>, server_config: &ServerConfig, job_provider: Arc<JobProvider>,
+         tls_fingerprint: Option<String>) -> StratumServer {
+    let time_to_live = Duration::from_secs(60 * 60 * 2);
+    StratumServer {
+      config: server_config.clone(),
+      app,
+      miner_connections: Mutex::new(
+        LruCache::with_expiry_duration_and_capacity(time_to_live, server_config.max_connections.unwrap_or(10000))
+      ),
+      sessions: Mutex::new(LruCache::with_expiry_duration(Duration::from_secs(SESSION_MEMORY))),
+      job_provider,
+      tls_fingerprint,
+    }
+  }
+
+  fn getminer(&self, params: &Map<String, Value>) -> Option<Arc<Miner>> {
+    if let Some(&Value::String(ref id)) = params.get("id") {
+      if !codec::is_valid_uuid(id) {
+        return None;
+      }
+      self.miner_connections.lock().unwrap().get(id)
+        .map(|miner| miner.clone())
+    } else {
+      None
+    }
+  }
+
+  fn refresh_all_jobs(&self) {
+    let mut miner_connections = self.miner_connections.lock().unwrap();
+    debug!("Refreshing {} jobs.", miner_connections.len());
+    let disconnected: Vec<String> = miner_connections.iter()
+      .filter(|&(_, miner)| miner.is_closed() || !miner.retarget_job(&self.job_provider))
+      .map(|(id, _)| id.to_owned())
+      .collect();
+    for id in disconnected {
+      if let Some(miner) = miner_connections.remove(&id) {
+        self.end_session(&miner);
+      }
+    }
+  }
+
+  /// Drops every miner's jobs once the pool freezes, since they build on what is probably an old
+  /// tip.  Miners get new jobs from refresh_all_jobs when the daemon recovers.
+  fn drop_all_jobs(&self) {
+    let miner_connections = self.miner_connections.lock().unwrap();
+    debug!("Dropping the jobs of {} miners.", miner_connections.len());
+    for (_, miner) in miner_connections.peek_iter() {
+      miner.drop_jobs();
+    }
+  }
+
+  /// Remembers the state of a session that is going away, in case the worker reconnects.
+  fn end_session(&self, miner: &Miner) {
+    miner.close();
+    self.sessions.lock().unwrap().insert(SessionKey::of(miner), miner.take_session_state());
+  }
+
+  /// Finds the state that a reconnecting worker left behind.  The transports mark a connection
+  /// closed as soon as the miner goes away, but the pool only cleans it up on its next job refresh,
+  /// so until then we take over its state directly.
+  fn take_session(&self, key: &SessionKey) -> Option<SessionState> {
+    let mut miner_connections = self.miner_connections.lock().unwrap();
+    let closed_id = miner_connections.peek_iter()
+      .find(|&(_, miner)| miner.is_closed() && &SessionKey::of(miner) == key)
+      .map(|(id, _)| id.to_owned());
+    if let Some(miner) = closed_id.and_then(|id| miner_connections.remove(&id)) {
+      return Some(miner.take_session_state());
+    }
+    self.sessions.lock().unwrap().remove(key)
+  }
+
+  /// Checks the per-address and per-IP connection limits for a new session on this port.
+  fn check_connection_limits(&self, address: &str, ip: &IpAddr) -> Result<()> {
+    let miner_connections = self.miner_connections.lock().unwrap();
+    if let Some(max_connections) = self.config.max_connections_per_address {
+      let address_connections = miner_connections.peek_iter()
+        .filter(|&(_, miner)| !miner.is_closed() && miner.address == address)
+        .count();
+      if address_connections >= max_connections {
+        return Err(Error::invalid_params("Too many connections for this wallet address"));
+      }
+    }
+    if let Some(max_connections) = self.config.max_connections_per_ip {
+      let ip_connections = miner_connections.peek_iter()
+        .filter(|&(_, miner)| !miner.is_closed() && &miner.connection.peer_addr.ip() == ip)
+        .count();
+      if ip_connections >= max_connections {
+        return Err(Error::invalid_params("Too many connections from this IP"));
+      }
+    }
+    Ok(())
+  }
+
+  /// Miners that announce their algorithms at login need at least one that matches ours, since
+  /// anything else would only get them banned for invalid shares.  Miners that don't announce any
+  /// are assumed to know what they're doing.
+  fn check_algo_support(&self, params: &Map<String, Value>) -> Result<()> {
+    if let Some(&Value::Array(ref algos)) = params.get("algo") {
+      let algos: Vec<&str> = algos.iter().filter_map(|algo| algo.as_str()).collect();
+      let hash_type = self.job_provider.hash_type();
+      let variant = self.job_provider.variant();
+      if !hash_type.supported_by(variant, &algos) {
+        debug!("Rejecting miner with algorithms {:?}, agent {:?}", algos, params.get("agent"));
+        return Err(Error::invalid_params(format!(
+          "This pool mines {}, which the miner does not support", hash_type.algo(variant))));
+      }
+    }
+    Ok(())
+  }
+
+  fn login(&self, params: Map<String, Value>, meta: Meta) -> Result<Value> {
+    if let None = meta.peer_addr {
+      return Err(Error::internal_error());
+    }
+    if let Some(ban) = self.find_ban(&meta.peer_addr.unwrap().ip(), None) {
+      return self.ban_message(&ban);
+    }
+    if let Some(&Value::String(ref login)) = params.get("login") {
+      let (address, alias, requested_difficulty) = parse_login(login);
+      let rig_id = match params.get("rigid") {
+        Some(&Value::String(ref rig_id)) if !rig_id.is_empty() => {
+          Some(rig_id.chars().take(100).collect::<String>())
+        },
+        _ => None,
+      };
+      // Miners that set a rig ID usually leave it out of the login, so it names the worker instead.
+      let alias = alias.map(|a| a.to_owned()).or_else(|| rig_id.to_owned());
+      if alias.to_owned().map_or(false, |a| a.len() > 100) {
+        return Err(Error::invalid_params("Miner alias can be at most 100 characters"));
+      }
+      let agent = match params.get("agent") {
+        Some(&Value::String(ref agent)) => Some(agent.chars().take(255).collect::<String>()),
+        _ => None,
+      };
+      if !self.app.address_pattern.is_match(address) {
+        return Err(Error::invalid_params("Invalid wallet address in login parameters"));
+      }
+      if let Some(ban) = self.find_ban(&meta.peer_addr.unwrap().ip(), Some(address)) {
+        return self.ban_message(&ban);
+      }
+      self.check_connection_limits(address, &meta.peer_addr.unwrap().ip())?;
+      self.check_algo_support(&params)?;
+      // Proxies announce themselves in the agent string, the same way they do with node-based pools.
+      let proxy = agent.as_ref()
+        .map_or(false, |agent| PROXY_AGENTS.iter().any(|proxy| agent.contains(proxy)));
+      let difficulty = match requested_difficulty {
+        Some(difficulty) => min(max(difficulty, self.config.min_difficulty.unwrap_or(1)),
+                                self.config.max_difficulty.unwrap_or(u64::max_value())),
+        None => self.config.starting_difficulty,
+      };
+      let connection = MinerConnection {
+        peer_addr: meta.peer_addr.unwrap(),
+        sender: meta.sender.unwrap().clone(),
+        closed: meta.closed.unwrap_or_else(|| Arc::new(AtomicBool::new(false))),
+      };
+      let miner = Miner::new(address, alias, agent, rig_id, connection, difficulty as usize,
+                             requested_difficulty.is_some(), self.config.nicehash, proxy);
+      if let Some(mut session) = self.take_session(&SessionKey::of(&miner)) {
+        // Workers that haven't had a valid share yet start over, and so do workers that asked for
+        // a difficulty this time.
+        if session.trust > 0 && requested_difficulty.is_none() {
+          debug!("Resuming session for {} at difficulty {}", address, session.difficulty);
+          session.difficulty = min(max(session.difficulty, self.config.min_difficulty.unwrap_or(1)),
+                                   self.config.max_difficulty.unwrap_or(u64::max_value()));
+          miner.resume(session);
+        }
+      }
+      let mut response = json!({
+        "id": &miner.id,
+        "extensions": ["algo", "keepalive"],
+        "status": "OK",
+      });
+      // While the pool is frozen, miners log in without a job, and get one when the daemon
+      // recovers.
+      if !self.job_provider.is_frozen() {
+        response["job"] = miner.get_job(&self.job_provider)?;
+      }
+      self.miner_connections.lock().unwrap().insert(miner.id.to_owned(), Arc::new(miner));
+      Ok(response)
+    } else {
+      Err(Error::invalid_params("Login address required"))
+    }
+  }
+
+  fn getjob(&self, params: Map<String, Value>, _meta: Meta) -> Result<Value> {
+    if let Some(miner) = self.getminer(&params) {
+      miner.get_job(&self.job_provider)
+    }
+    else {
+      Err(rejection_error(Rejection::Unauthenticated))
+    }
+  }
+
+  /// xmrig sends this to keep its connection alive while it works on high-difficulty shares.  Like
+  /// any other request, it restarts the connection's idle timeout.
+  fn keepalived(&self, params: Map<String, Value>, _meta: Meta) -> Result<Value> {
+    if let Some(_) = self.getminer(&params) {
+      Ok(json!({
+        "status": "KEEPALIVED",
+      }))
+    }
+    else {
+      Err(rejection_error(Rejection::Unauthenticated))
+    }
+  }
+
+  fn ban_ip(&self, ip: &IpAddr) {
+    // We only issue short automatic bans - these are just to keep people from being able to
+    // cheaply overload the server by falsely submitting low-difficulty shares.
+    let ban_length = Duration::from_secs(60 * 5);
+    self.app.bans.ban(&self.app.db, &BanTarget::Ip(ip.to_owned()), "Invalid share",
+                      Some(ban_length), BanSource::Automatic);
+  }
+
+  fn find_ban(&self, ip: &IpAddr, address: Option<&str>) -> Option<MinerBan> {
+    self.app.bans.find(&BanTarget::Ip(ip.to_owned())).or_else(|| {
+      address.and_then(|address| self.app.bans.find(&BanTarget::Address(address.to_owned())))
+    })
+  }
+
+  fn ban_message(&self, ban: &MinerBan) -> Result<Value> {
+    match BanSource::from(ban.source) {
+      BanSource::Automatic => Err(Error::invalid_params(
+        "Your IP has received a short temporary ban due to an invalid share.  Usually this is \
+         due to a mistake configuring xmr-stak/xmrig/cpuminer/etc.  Typically the relevant config \
+         option is named something like 'currency' or 'hashtype' - that value in your config needs \
+         to match up with the pool you are connecting to."
+      )),
+      BanSource::Manual => Err(Error::invalid_params(
+        format!("You have been banned by the pool operator: {}", ban.reason)
+      )),
+    }
+  }
+
+  /// Logs a request with invalid fields, and counts it against the connection.
+  fn malformed_request(&self, meta: &Meta, reason: &'static str) -> Result<Value> {
+    info!("Malformed request from {:?}: {}", meta.peer_addr, reason);
+    if let Some(ref malformed_requests) = meta.malformed_requests {
+      let malformed = malformed_requests.fetch_add(1, Ordering::Relaxed) + 1;
+      if malformed >= codec::MAX_MALFORMED_REQUESTS {
+        if let Some(ref closed) = meta.closed {
+          closed.store(true, Ordering::Relaxed);
+        }
+      }
+    }
+    Err(Error::invalid_params(reason))
+  }
+
+  fn submit(&self, params: Map<String, Value>, meta: Meta) -> Result<Value> {
+    let addr = match meta.peer_addr {
+      Some(addr) => addr,
+      None => return Err(Error::internal_error()),
+    };
+    if let Some(ban) = self.find_ban(&addr.ip(), None) {
+      return self.ban_message(&ban);
+    }
+    match params.get("id") {
+      Some(&Value::String(ref id)) if codec::is_valid_uuid(id) => {},
+      _ => return self.malformed_request(&meta, "id must be the miner ID from login"),
+    }
+    match params.get("job_id") {
+      Some(&Value::String(ref job_id)) if codec::is_valid_uuid(job_id) => {},
+      _ => return self.malformed_request(&meta, "job_id must be a job ID sent to this miner"),
+    }
+    match params.get("nonce") {
+      Some(&Value::String(ref nonce)) if codec::is_valid_nonce(nonce) => {},
+      _ => return self.malformed_request(&meta, "nonce must be 8 hex digits"),
+    }
+
+    let miner = match self.getminer(&params) {
+      Some(miner) => miner,
+      None => return Err(rejection_error(Rejection::Unauthenticated)),
+    };
+    if !self.app.address_pattern.is_match(&miner.address) {
+      return Err(Error::invalid_params("Miner ID must be alphanumeric"));
+    }
+    if let Some(ban) = self.find_ban(&addr.ip(), Some(&miner.address)) {
+      return self.ban_message(&ban);
+    }
+    let job = match params.get("job_id") {
+      Some(&Value::String(ref job_id)) => {
+        miner.jobs.lock().unwrap().get(job_id).map(|job| job.clone())
+      },
+      _ => None,
+    };
+    let job = match job {
+      Some(ref job) if self.job_provider.is_orphaned(job) => {
+        return Err(rejection_error(Rejection::Expired));
+      },
+      Some(job) => job,
+      None => return Err(rejection_error(Rejection::Expired)),
+    };
+    let nonce = match params.get("nonce") {
+      Some(&Value::String(ref nonce)) => nonce,
+      _ => return self.malformed_request(&meta, "nonce must be 8 hex digits"),
+    };
+    let result = if miner.proxy {
+      // xmr-node-proxy sends the nonces that it wrote into the reserved space as numbers.
+      let pool_nonce = params.get("poolNonce").and_then(|nonce| nonce.as_u64());
+      let worker_nonce = params.get("workerNonce").and_then(|nonce| nonce.as_u64());
+      let max_nonce = u32::max_value() as u64;
+      match (pool_nonce, worker_nonce) {
+        (Some(pool_nonce), Some(worker_nonce))
+          if pool_nonce <= max_nonce && worker_nonce <= max_nonce => {
+          job.check_proxy_submission(pool_nonce as u32, worker_nonce as u32, nonce)
+        },
+        _ => {
+          return self.malformed_request(&meta, "Proxies must submit a poolNonce and workerNonce");
+        },
+      }
+    } else {
+      job.check_submission(nonce)
+    };
+    // Only accepted shares count towards the miner's hashrate, so that a miner sending in junk
+    // doesn't get its difficulty raised.
+    let retarget_now = match result {
+      JobResult::SharesRejected(_) => false,
+      _ => miner.adjust_difficulty(job.difficulty, &self.config),
+    };
+    if retarget_now {
+      // The new job reaches the miner just before the response to this submission, which is fine
+      // since jobs are sent as notifications.
+      miner.retarget_job(&self.job_provider);
+    }
+
+    match result {
+      JobResult::BlockFound(block) => {
+        miner.trust.fetch_add(1, Ordering::Relaxed);
+        match self.app.daemon.submit_block(&block.blob) {
+          Ok(_) => self.app.db.block_found(block, &miner, &job),
+          Err(err) => warn!("Failed to send block to daemon: {:?}", err)
+        };
+        Ok(share_accepted())
+      },
+      JobResult::SharesAccepted => {
+        miner.trust.fetch_add(1, Ordering::Relaxed);
+        self.app.db.shares_accepted(&miner, &job);
+        Ok(share_accepted())
+      },
+      JobResult::SharesRejected(rejection) => {
+        if rejection.bans_miner() {
+          info!("Banning IP {} due to bad share: {}", addr.ip(), rejection.message());
+          self.ban_ip(&addr.ip());
+          if let Some(closed) = meta.closed {
+            closed.store(true, Ordering::Relaxed);
+          }
+        }
+        Err(rejection_error(rejection))
+      },
+    }
+  }
+}
+
+/// The response to an accepted share, in the format that node-cryptonote-pool uses and that most
+/// miners check for.
+fn share_accepted() -> Value {
+  json!({
+    "status": "OK",
+  })
+}
+
+fn rejection_error(rejection: Rejection) -> Error {
+  Error {
+    code: ErrorCode::ServerError(-1),
+    message: rejection.message().to_owned(),
+    data: None,
+  }
+}
+
+/// Splits a login into the wallet address, the worker alias, and the fixed difficulty requested by
+/// the miner.  Aliases can come after either a ':' or a '.', and a fixed difficulty is requested by
+/// adding '+difficulty' to the end, like 'address.worker+50000'.
+fn parse_login(login: &str) -> (&str, Option<&str>, Option<u64>) {
+  let (login, requested_difficulty) = match login.rfind('+') {
+    Some(plus) => match login[(plus + 1)..].parse::<u64>() {
+      Ok(difficulty) if difficulty > 0 => (&login[..plus], Some(difficulty)),
+      _ => (login, None),
+    },
+    None => (login, None),
+  };
+  // Wallet addresses are base58, so they never contain either separator.
+  match login.find(|c| c == ':' || c == '.') {
+    Some(separator) => (&login[..separator], Some(&login[(separator + 1)..]), requested_difficulty),
+    None => (login, None, requested_difficulty),
+  }
+}
+
+/// The jsonrpc_macros crate would provide some nice macros, but is strict about protocol versions.
+/// Some mining software doesn't send over the required protocol version field, but sends its
+/// parameters in a map.  So we need to route permissively using add_method_with_meta, and parse
+/// parameters as a map no matter what version the miner says it uses.
+macro_rules! route_permissive {
+  ( $route:expr, $handler:ident, $server:ident, $io:ident ) => {
+    let handled_ref = $server.clone();
+    $io.add_method_with_meta($route, move |params, meta: Meta| {
+      if let Some(ref timeout) = meta.timeout {
+        timeout.request();
+      }
+      // The transport only shuts a closed connection down on its next sweep, and until then we
+      // stop answering it.
+      if meta.is_closed() {
+        return Err(Error::invalid_request());
+      }
+      match params {
+        Params::Map(map) => handled_ref.$handler(map, meta),
+        _ => Err(Error::invalid_params("Expected a params map")),
+      }
+    });
+  }
+}
+
+fn build_io(pool_server: &Arc<StratumServer>) -> MetaIoHandler<Meta> {
+  let mut io = MetaIoHandler::with_compatibility(Compatibility::Both);
+  route_permissive!("login", login, pool_server, io);
+  route_permissive!("getjob", getjob, pool_server, io);
+  route_permissive!("submit", submit, pool_server, io);
+  route_permissive!("keepalived", keepalived, pool_server, io);
+  io
+}
+
+/// The stratum servers for all of the configured ports.
+pub struct Stratum {
+  servers: Vec<Arc<StratumServer>>,
+  job_provider: Arc<JobProvider>,
+}
+
+impl Stratum {
+  /// Whether the daemon looks stuck, and if so, whether miners are still getting jobs.
+  pub fn daemon_state(&self) -> Value {
+    json!({
+      "stale": self.job_provider.stale_reason(),
+      "jobs_frozen": self.job_provider.is_frozen(),
+    })
+  }
+
+  /// The TLS certificate fingerprint of the given port, if it has tls enabled.
+  pub fn tls_fingerprint(&self, port: u16) -> Option<String> {
+    self.servers.iter().find(|server| server.config.port == port)
+      .and_then(|server| server.tls_fingerprint.clone())
+  }
+
+  /// How the block reward changed over the most recent templates.
+  pub fn template_rewards(&self) -> Vec<TemplateReward> {
+    self.job_provider.reward_history()
+  }
+
+  /// Summaries of the sessions that are currently connected for the given address.
+  pub fn workers(&self, address: &str) -> Vec<Value> {
+    let mut workers = Vec::new();
+    for server in self.servers.iter() {
+      for (_, miner) in server.miner_connections.lock().unwrap().peek_iter() {
+        if miner.address == address {
+          workers.push(json!({
+            "alias": miner.alias,
+            "agent": miner.agent,
+            "rig_id": miner.rig_id,
+            "port": server.config.port,
+            "difficulty": miner.difficulty.load(Ordering::Relaxed),
+            "fixed_difficulty": miner.fixed_difficulty,
+          }));
+        }
+      }
+    }
+    workers
+  }
+}
+
+/// Starts listening on all of the configured ports.
+pub fn init(app_ref: Arc<App>) -> Arc<Stratum> {
+  let job_provider = Arc::new(JobProvider::new(app_ref.clone()));
+  let servers: Vec<Arc<StratumServer>> = app_ref.config.ports.iter().map(|server_config| {
+    let tls_identity = if server_config.tls {
+      Some(tls::acceptor(server_config).unwrap())
+    } else {
+      None
+    };
+    let tls_fingerprint = tls_identity.as_ref().and_then(|identity| identity.fingerprint.clone());
+    let pool_server: Arc<StratumServer> = Arc::new(
+      StratumServer::new(app_ref.clone(), server_config, job_provider.clone(), tls_fingerprint)
+    );
+    let timeouts = Timeouts::new(server_config);
+    if server_config.transport == Transport::WebSocket {
+      if server_config.tls {
+        panic!("Port {} is a websocket port, which doesn't support tls.  Browser miners can still \
+                use wss:// through a TLS-terminating proxy.", server_config.port);
+      }
+      if server_config.proxy_protocol {
+        panic!("Port {} is a websocket port, which doesn't support proxy_protocol.",
+               server_config.port);
+      }
+      let io = Arc::new(build_io(&pool_server));
+      for address in server_config.bind_addresses() {
+        websocket::serve(io.clone(), &address, timeouts);
+      }
+    } else {
+      let io = Arc::new(build_io(&pool_server));
+      let acceptor = tls_identity.map(|identity| identity.acceptor);
+      for address in server_config.bind_addresses() {
+        if let Err(err) = stream::serve(io.clone(), &address, acceptor.clone(),
+                                        server_config.proxy_protocol, timeouts) {
+          panic!("Failed to listen on {}: {:?}", address, err);
+        }
+      }
+    }
+    pool_server
+  }).collect();
+  Arc::new(Stratum {
+    servers,
+    job_provider,
+  })
+}
+
+/// Keeps the block template and jobs up to date, and processes found blocks and payments.  This
+/// runs forever, waking up whenever the daemon notifies us of a block, and otherwise polls it every
+/// couple of seconds.
+pub fn run(app_ref: Arc<App>, stratum: Arc<Stratum>) {
+  let unlocker = Unlocker::new(app_ref.clone());
+  let job_provider = &stratum.job_provider;
+  let servers = &stratum.servers;
+  let refresh_interval =
+    Duration::from_secs(app_ref.config.template_refresh_interval.unwrap_or(20));
+  let mut last_refresh = Instant::now();
+  loop {
+    let new_template = job_provider.fetch_new_template();
+    let staleness = job_provider.check_staleness();
+    if staleness == Staleness::Stale && job_provider.is_frozen() {
+      for server in servers.iter() {
+        server.drop_all_jobs();
+      }
+    }
+    let recovered = staleness == Staleness::Recovered;
+    if new_template || recovered || last_refresh.elapsed() >= refresh_interval {
+      debug!("Refreshing jobs on {} servers", servers.len());
+      for server in servers.iter() {
+        server.refresh_all_jobs();
+      }
+      last_refresh = Instant::now();
+    }
+    unlocker.refresh();
+    app_ref.bans.prune_expired(&app_ref.db);
+    if app_ref.block_notifier.wait(Duration::from_millis(2000)) {
+      debug!("Daemon notified us of a new block");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use stratum::*;
+
+  #[test]
+  fn test_parse_login() {
+    assert_eq!(parse_login("address"), ("address", None, None));
+    assert_eq!(parse_login("address:rig1"), ("address", Some("rig1"), None));
+    assert_eq!(parse_login("address.rig1"), ("address", Some("rig1"), None));
+    assert_eq!(parse_login("address+50000"), ("address", None, Some(50000)));
+    assert_eq!(parse_login("address.rig1+50000"), ("address", Some("rig1"), Some(50000)));
+    assert_eq!(parse_login("address:rig.1+50000"), ("address", Some("rig.1"), Some(50000)));
+    // A suffix that isn't a usable difficulty is left as part of the alias.
+    assert_eq!(parse_login("address:rig+fast"), ("address", Some("rig+fast"), None));
+    assert_eq!(parse_login("address+0"), ("address+0", None, None));
+  }
+}
@@ -0,0 +1,138 @@
+use std::io;
+use std::io::{Read, Error, ErrorKind};
+use std::net::{SocketAddr, IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// The longest possible v1 header, including the trailing CRLF.
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: [u8; 12] = [
+  0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+
+fn invalid(message: &str) -> Error {
+  Error::new(ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Reads a PROXY protocol header (either the v1 text format or the v2 binary format) from the start
+/// of a connection, and returns the client address that the load balancer passed on.  The address
+/// is None when the balancer connected on its own behalf, like for health checks.  This reads
+/// exactly the header, so whatever follows can be handed on to TLS or the JSON-RPC reader.
+pub fn read_header<R: Read>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+  let mut first = [0u8; 1];
+  stream.read_exact(&mut first)?;
+  match first[0] {
+    b'P' => read_v1(stream),
+    0x0d => read_v2(stream),
+    _ => Err(invalid("Connection did not start with a PROXY protocol header")),
+  }
+}
+
+fn read_v1<R: Read>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+  // The header has to be read a byte at a time, since reading any further would eat into the
+  // miner's own data.
+  let mut header = vec![b'P'];
+  let mut byte = [0u8; 1];
+  while !header.ends_with(b"\r\n") {
+    if header.len() >= V1_MAX_LENGTH {
+      return Err(invalid("PROXY v1 header is too long"));
+    }
+    stream.read_exact(&mut byte)?;
+    header.push(byte[0]);
+  }
+  let header = String::from_utf8(header).map_err(|_| invalid("PROXY v1 header is not text"))?;
+  // Fields are PROXY, the protocol, the source and destination addresses, and their ports.
+  let fields: Vec<&str> = header[..(header.len() - 2)].split(' ').collect();
+  if fields.len() >= 2 && fields[0] == "PROXY" && fields[1] == "UNKNOWN" {
+    return Ok(None);
+  }
+  if fields.len() != 6 || fields[0] != "PROXY" || (fields[1] != "TCP4" && fields[1] != "TCP6") {
+    return Err(invalid("Malformed PROXY v1 header"));
+  }
+  let ip: IpAddr = fields[2].parse()
+    .map_err(|_| invalid("Invalid source address in PROXY header"))?;
+  let port: u16 = fields[4].parse()
+    .map_err(|_| invalid("Invalid source port in PROXY header"))?;
+  Ok(Some(SocketAddr::new(ip, port)))
+}
+
+fn read_v2<R: Read>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+  let mut header = [0u8; 16];
+  header[0] = 0x0d;
+  stream.read_exact(&mut header[1..])?;
+  if header[..12] != V2_SIGNATURE {
+    return Err(invalid("Invalid PROXY v2 signature"));
+  }
+  if header[12] >> 4 != 2 {
+    return Err(invalid("Unsupported PROXY protocol version"));
+  }
+  let length = ((header[14] as usize) << 8) | header[15] as usize;
+  let mut addresses = vec![0u8; length];
+  stream.read_exact(&mut addresses)?;
+  // The LOCAL command is for connections that the balancer makes itself.
+  if header[12] & 0x0f == 0 {
+    return Ok(None);
+  }
+  let port = |offset: usize| ((addresses[offset] as u16) << 8) | addresses[offset + 1] as u16;
+  match header[13] {
+    // TCP over IPv4, with the source and destination addresses followed by their ports.
+    0x11 if length >= 12 => {
+      let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+      Ok(Some(SocketAddr::new(IpAddr::V4(ip), port(8))))
+    },
+    // TCP over IPv6, laid out the same way.
+    0x21 if length >= 36 => {
+      let mut octets = [0u8; 16];
+      octets.copy_from_slice(&addresses[..16]);
+      Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port(32))))
+    },
+    // Anything else, like UNIX sockets, doesn't carry an address that we could ban.
+    _ => Ok(None),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use stratum::proxy_protocol::*;
+  use std::io::Cursor;
+
+  #[test]
+  fn test_v1_header() {
+    let header = b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 3333\r\n{\"id\":1}\n";
+    let mut stream = Cursor::new(header.to_vec());
+    assert_eq!(read_header(&mut stream).unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "{\"id\":1}\n");
+
+    let mut stream = Cursor::new(b"PROXY TCP6 2001:db8::1 2001:db8::2 51234 3333\r\n".to_vec());
+    assert_eq!(read_header(&mut stream).unwrap(),
+               Some("[2001:db8::1]:51234".parse().unwrap()));
+    let mut stream = Cursor::new(b"PROXY UNKNOWN\r\n".to_vec());
+    assert_eq!(read_header(&mut stream).unwrap(), None);
+    let mut stream = Cursor::new(b"PROXY TCP4 nonsense\r\n".to_vec());
+    assert!(read_header(&mut stream).is_err());
+    let mut stream = Cursor::new(b"{\"id\":1,\"method\":\"login\"}\n".to_vec());
+    assert!(read_header(&mut stream).is_err());
+  }
+
+  #[test]
+  fn test_v2_header() {
+    let mut header = V2_SIGNATURE.to_vec();
+    // PROXY command over TCP4, with 12 bytes of addresses and ports.
+    header.extend_from_slice(&[0x21, 0x11, 0x00, 0x0c]);
+    header.extend_from_slice(&[203, 0, 113, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x0d, 0x05]);
+    header.extend_from_slice(b"{\"id\":1}\n");
+    let mut stream = Cursor::new(header);
+    assert_eq!(read_header(&mut stream).unwrap(), Some("203.0.113.7:51234".parse().unwrap()));
+    let mut rest = String::new();
+    stream.read_to_string(&mut rest).unwrap();
+    assert_eq!(rest, "{\"id\":1}\n");
+
+    let mut local = V2_SIGNATURE.to_vec();
+    local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+    assert_eq!(read_header(&mut Cursor::new(local)).unwrap(), None);
+
+    let mut bad_version = V2_SIGNATURE.to_vec();
+    bad_version.extend_from_slice(&[0x11, 0x11, 0x00, 0x00]);
+    assert!(read_header(&mut Cursor::new(bad_version)).is_err());
+  }
+}
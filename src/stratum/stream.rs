@@ -0,0 +1,264 @@
+use std::io;
+use std::io::{Read, Write, ErrorKind};
+use std::net::{SocketAddr, TcpListener, TcpStream, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use jsonrpc_core::MetaIoHandler;
+use jsonrpc_core::futures::Stream;
+use jsonrpc_core::futures::sync::mpsc::{channel, Receiver, Sender};
+use openssl::ssl::{SslAcceptor, SslStream};
+use stratum::Meta;
+use stratum::codec::*;
+use stratum::proxy_protocol;
+use stratum::timeouts::{ConnectionTimeout, ConnectionTracker, Timeouts};
+
+/// How long a miner gets to finish the TLS handshake.
+const HANDSHAKE_TIMEOUT: u64 = 10;
+/// How often a TLS connection stops waiting on the miner to check for jobs to push.  This is the
+/// worst-case delay added to a job notification on TLS ports.
+const TLS_POLL_INTERVAL_MS: u64 = 50;
+/// How often each port shuts down the connections that have timed out or that the pool closed.
+const SWEEP_INTERVAL_MS: u64 = 1000;
+
+/// Serves line-delimited JSON-RPC with a thread per connection, optionally wrapped in TLS.  The
+/// connection is closed when the miner disconnects, when it times out, or when the pool sets the
+/// closed flag that it gets in the request metadata.  With proxy_protocol set, each connection has
+/// to start with a PROXY header from the load balancer, which gives us the miner's real address.
+pub fn serve(io: Arc<MetaIoHandler<Meta>>, address: &SocketAddr, acceptor: Option<Arc<SslAcceptor>>,
+             proxy_protocol: bool, timeouts: Timeouts) -> io::Result<()> {
+  let listener = TcpListener::bind(address)?;
+  let connections = Arc::new(ConnectionTracker::new());
+  let swept_connections = connections.clone();
+  thread::spawn(move || loop {
+    thread::sleep(Duration::from_millis(SWEEP_INTERVAL_MS));
+    let expired = swept_connections.sweep(|socket: &TcpStream| {
+      let _ = socket.shutdown(Shutdown::Both);
+    });
+    if expired > 0 {
+      debug!("Closed {} connections that timed out", expired);
+    }
+  });
+  thread::spawn(move || {
+    for stream in listener.incoming() {
+      match stream {
+        Ok(stream) => {
+          let io = io.clone();
+          let acceptor = acceptor.clone();
+          let connections = connections.clone();
+          thread::spawn(move || {
+            handle_connection(io, stream, acceptor, proxy_protocol, timeouts, &connections)
+          });
+        },
+        Err(err) => debug!("Failed to accept stratum connection: {:?}", err),
+      }
+    }
+  });
+  Ok(())
+}
+
+fn handle_connection(io: Arc<MetaIoHandler<Meta>>, stream: TcpStream,
+                     acceptor: Option<Arc<SslAcceptor>>, proxy_protocol: bool, timeouts: Timeouts,
+                     connections: &ConnectionTracker<TcpStream>) {
+  let timeout = timeouts.start();
+  let closed = Arc::new(AtomicBool::new(false));
+  let (peer_addr, socket) = match (stream.peer_addr(), stream.try_clone()) {
+    (Ok(peer_addr), Ok(socket)) => (peer_addr, socket),
+    _ => return,
+  };
+  // From here on, the sweep shuts the socket down once the connection times out or gets closed,
+  // which also wakes up whichever thread is blocked on it.
+  match socket.try_clone() {
+    Ok(tracked_socket) => connections.track(timeout.clone(), closed.clone(), tracked_socket),
+    Err(_) => return,
+  }
+  serve_connection(io, peer_addr, stream, acceptor, proxy_protocol, timeout, closed.clone());
+  // Lets the pool know that the session is over, so that a reconnecting miner can resume it.
+  closed.store(true, Ordering::Relaxed);
+  let _ = socket.shutdown(Shutdown::Both);
+}
+
+fn serve_connection(io: Arc<MetaIoHandler<Meta>>, mut peer_addr: SocketAddr, mut stream: TcpStream,
+                    acceptor: Option<Arc<SslAcceptor>>, proxy_protocol: bool,
+                    timeout: Arc<ConnectionTimeout>, closed: Arc<AtomicBool>) {
+  if let Err(err) = stream.set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT))) {
+    debug!("Failed to set timeout on connection from {}: {:?}", peer_addr, err);
+    return;
+  }
+  if proxy_protocol {
+    // The balancer sends its header before anything else, including the TLS handshake.
+    match proxy_protocol::read_header(&mut stream) {
+      Ok(Some(client_addr)) => peer_addr = client_addr,
+      Ok(None) => {},
+      Err(err) => {
+        debug!("Invalid PROXY header from {}: {:?}", peer_addr, err);
+        return;
+      },
+    }
+  }
+
+  // Jobs get pushed to miners through a futures channel, which is what the pool keeps in each
+  // miner's connection.
+  let (sender, pushes) = channel::<String>(64);
+  let session = LineSession {
+    io,
+    peer_addr,
+    sender,
+    closed,
+    malformed_requests: Arc::new(AtomicUsize::new(0)),
+    timeout,
+    codec: LineCodec::new(MAX_LINE_LENGTH),
+  };
+  match acceptor {
+    Some(acceptor) => match acceptor.accept(stream) {
+      Ok(tls_stream) => serve_tls(session, tls_stream, pushes),
+      Err(err) => debug!("TLS handshake failed with {}: {:?}", peer_addr, err),
+    },
+    None => serve_plain(session, stream, pushes),
+  }
+}
+
+/// Plain TCP sockets can be split, so the connection thread blocks on reads, and jobs get written
+/// by the thread that receives them.
+fn serve_plain(mut session: LineSession, mut stream: TcpStream, pushes: Receiver<String>) {
+  if let Err(err) = stream.set_read_timeout(None) {
+    debug!("Failed to clear timeout on connection from {}: {:?}", session.peer_addr, err);
+    return;
+  }
+  let writer = match stream.try_clone() {
+    Ok(writer) => Arc::new(Mutex::new(writer)),
+    Err(_) => return,
+  };
+  let push_writer = writer.clone();
+  thread::spawn(move || {
+    for message in pushes.wait() {
+      match message {
+        Ok(message) => if write_line(&mut *push_writer.lock().unwrap(), &message).is_err() {
+          break;
+        },
+        Err(_) => break,
+      }
+    }
+  });
+
+  let mut chunk = [0u8; 4096];
+  loop {
+    match stream.read(&mut chunk) {
+      Ok(0) => break,
+      Ok(bytes_read) => {
+        let mut respond = |response: &str| write_line(&mut *writer.lock().unwrap(), response);
+        if !session.handle_input(&chunk[..bytes_read], &mut respond) {
+          break;
+        }
+      },
+      Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+      Err(err) => {
+        debug!("Lost connection to {}: {:?}", session.peer_addr, err);
+        break;
+      },
+    }
+  }
+}
+
+/// A TLS stream can't be split between threads, so rather than sharing the stream, we bridge the
+/// pushes into a channel that the connection thread checks whenever it stops waiting on the miner.
+fn serve_tls(mut session: LineSession, mut stream: SslStream<TcpStream>,
+             pushes: Receiver<String>) {
+  let poll_interval = Duration::from_millis(TLS_POLL_INTERVAL_MS);
+  if let Err(err) = stream.get_ref().set_read_timeout(Some(poll_interval)) {
+    debug!("Failed to set timeout on connection from {}: {:?}", session.peer_addr, err);
+    return;
+  }
+  let (push_sender, queued_pushes) = mpsc::channel::<String>();
+  thread::spawn(move || {
+    for message in pushes.wait() {
+      match message {
+        Ok(message) => if push_sender.send(message).is_err() {
+          break;
+        },
+        Err(_) => break,
+      }
+    }
+  });
+
+  let mut chunk = [0u8; 4096];
+  'connection: loop {
+    match stream.read(&mut chunk) {
+      Ok(0) => break,
+      Ok(bytes_read) => {
+        let mut respond = |response: &str| write_line(&mut stream, response);
+        if !session.handle_input(&chunk[..bytes_read], &mut respond) {
+          break;
+        }
+      },
+      Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {},
+      Err(err) => {
+        debug!("Lost connection to {}: {:?}", session.peer_addr, err);
+        break;
+      },
+    }
+    while let Ok(push) = queued_pushes.try_recv() {
+      if write_line(&mut stream, &push).is_err() {
+        break 'connection;
+      }
+    }
+  }
+}
+
+/// The stratum side of a connection, which splits what the miner sends into requests for the pool.
+struct LineSession {
+  io: Arc<MetaIoHandler<Meta>>,
+  peer_addr: SocketAddr,
+  sender: Sender<String>,
+  closed: Arc<AtomicBool>,
+  malformed_requests: Arc<AtomicUsize>,
+  timeout: Arc<ConnectionTimeout>,
+  codec: LineCodec,
+}
+
+impl LineSession {
+  /// Handles the requests that a chunk of input completes, passing each response to respond.
+  /// Returns false once the connection should be dropped.
+  fn handle_input(&mut self, input: &[u8], respond: &mut FnMut(&str) -> io::Result<()>) -> bool {
+    self.codec.feed(input);
+    loop {
+      let request = match self.codec.next_line() {
+        Ok(Some(request)) => request,
+        Ok(None) => return true,
+        Err(err) => {
+          info!("Dropping connection from {}: {:?}", self.peer_addr, err);
+          return false;
+        },
+      };
+      if let Err(reason) = validate_request(&request) {
+        // Still passed on, so that the miner gets the usual JSON-RPC error back.
+        info!("Malformed request from {}: {}", self.peer_addr, reason);
+        self.malformed_requests.fetch_add(1, Ordering::Relaxed);
+      }
+      let meta = Meta {
+        peer_addr: Some(self.peer_addr),
+        sender: Some(self.sender.clone()),
+        closed: Some(self.closed.clone()),
+        malformed_requests: Some(self.malformed_requests.clone()),
+        timeout: Some(self.timeout.clone()),
+      };
+      if let Some(response) = self.io.handle_request_sync(&request, meta) {
+        if respond(&response).is_err() {
+          return false;
+        }
+      }
+      if self.malformed_requests.load(Ordering::Relaxed) >= MAX_MALFORMED_REQUESTS {
+        warn!("Dropping connection from {} after too many malformed requests", self.peer_addr);
+        return false;
+      }
+    }
+  }
+}
+
+fn write_line<W: Write + ?Sized>(connection: &mut W, message: &str) -> io::Result<()> {
+  connection.write_all(message.as_bytes())?;
+  connection.write_all(b"\n")?;
+  connection.flush()
+}
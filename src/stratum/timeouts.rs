@@ -0,0 +1,134 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use config::ServerConfig;
+
+/// Seconds without a request before a connection gets closed, unless the port sets its own
+/// idle_timeout.
+const IDLE_TIMEOUT: u64 = 60 * 10;
+
+/// Seconds that a new connection has to send its first request, unless the port sets its own
+/// first_request_timeout.  Miners log in as soon as they connect, so a connection that stays quiet
+/// for this long is a port scanner or has already died.
+const FIRST_REQUEST_TIMEOUT: u64 = 30;
+
+/// The timeouts for connections on one port.
+#[derive(Clone, Copy)]
+pub struct Timeouts {
+  first_request: Duration,
+  idle: Duration,
+}
+
+impl Timeouts {
+  pub fn new(config: &ServerConfig) -> Timeouts {
+    Timeouts {
+      first_request: Duration::from_secs(
+        config.first_request_timeout.unwrap_or(FIRST_REQUEST_TIMEOUT)),
+      idle: Duration::from_secs(config.idle_timeout.unwrap_or(IDLE_TIMEOUT)),
+    }
+  }
+
+  /// Starts the clock on a new connection.
+  pub fn start(&self) -> Arc<ConnectionTimeout> {
+    Arc::new(ConnectionTimeout {
+      timeouts: *self,
+      opened: Instant::now(),
+      last_request: Mutex::new(None),
+    })
+  }
+}
+
+/// When a connection opened and last sent a request.  Transports close connections once this
+/// expires, whether or not they ever logged in.
+pub struct ConnectionTimeout {
+  timeouts: Timeouts,
+  opened: Instant,
+  last_request: Mutex<Option<Instant>>,
+}
+
+impl ConnectionTimeout {
+  pub fn request(&self) {
+    *self.last_request.lock().unwrap() = Some(Instant::now());
+  }
+
+  pub fn expired(&self) -> bool {
+    self.expired_at(Instant::now())
+  }
+
+  fn expired_at(&self, now: Instant) -> bool {
+    match *self.last_request.lock().unwrap() {
+      Some(last_request) => now.duration_since(last_request) > self.timeouts.idle,
+      None => now.duration_since(self.opened) > self.timeouts.first_request,
+    }
+  }
+}
+
+/// The open connections of a port, along with whatever the transport needs to shut each one down.
+/// Connection threads block on reads until the miner sends something, so rather than watching
+/// their own timeouts, they get swept every so often.
+pub struct ConnectionTracker<S> {
+  connections: Mutex<Vec<(Arc<ConnectionTimeout>, Arc<AtomicBool>, S)>>,
+}
+
+impl<S> ConnectionTracker<S> {
+  pub fn new() -> ConnectionTracker<S> {
+    ConnectionTracker {
+      connections: Mutex::new(Vec::new()),
+    }
+  }
+
+  pub fn track(&self, timeout: Arc<ConnectionTimeout>, closed: Arc<AtomicBool>, socket: S) {
+    self.connections.lock().unwrap().push((timeout, closed, socket));
+  }
+
+  /// Shuts down the connections that have timed out or been closed, and forgets them.  Returns how
+  /// many timed out.
+  pub fn sweep<F: FnMut(&S)>(&self, mut shutdown: F) -> usize {
+    let mut expired = 0;
+    self.connections.lock().unwrap().retain(|&(ref timeout, ref closed, ref socket)| {
+      if !closed.load(Ordering::Relaxed) {
+        if !timeout.expired() {
+          return true;
+        }
+        closed.store(true, Ordering::Relaxed);
+        expired += 1;
+      }
+      shutdown(socket);
+      false
+    });
+    expired
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use stratum::timeouts::*;
+
+  #[test]
+  fn test_connection_timeout() {
+    let timeouts = Timeouts {
+      first_request: Duration::from_secs(30),
+      idle: Duration::from_secs(600),
+    };
+    let timeout = timeouts.start();
+    let opened = timeout.opened;
+    // Connections that never send anything only get the first request timeout.
+    assert!(!timeout.expired_at(opened + Duration::from_secs(30)));
+    assert!(timeout.expired_at(opened + Duration::from_secs(31)));
+
+    timeout.request();
+    let last_request = timeout.last_request.lock().unwrap().unwrap();
+    assert!(!timeout.expired_at(last_request + Duration::from_secs(600)));
+    assert!(timeout.expired_at(last_request + Duration::from_secs(601)));
+
+    let tracker = ConnectionTracker::new();
+    let closed = Arc::new(AtomicBool::new(false));
+    tracker.track(timeouts.start(), closed.clone(), "open");
+    tracker.track(timeouts.start(), Arc::new(AtomicBool::new(true)), "closed");
+    let mut shut_down = Vec::new();
+    assert_eq!(tracker.sweep(|socket| shut_down.push(*socket)), 0);
+    assert_eq!(shut_down, vec!["closed"]);
+    assert_eq!(tracker.connections.lock().unwrap().len(), 1);
+    assert!(!closed.load(Ordering::Relaxed));
+  }
+}
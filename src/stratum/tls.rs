@@ -0,0 +1,43 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::sync::Arc;
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslAcceptor, SslMethod, SslFiletype};
+use openssl::x509::X509;
+use config::ServerConfig;
+use crypto::cryptonote_utils::bytes_to_hex;
+
+/// A TLS port's acceptor, along with its certificate's fingerprint for the API to show.
+pub struct TlsIdentity {
+  pub acceptor: Arc<SslAcceptor>,
+  pub fingerprint: Option<String>,
+}
+
+pub fn acceptor(config: &ServerConfig) -> Result<TlsIdentity, String> {
+  let certificate = config.tls_certificate.as_ref()
+    .ok_or(format!("Port {} has tls enabled, but no tls_certificate", config.port))?;
+  let key = config.tls_key.as_ref()
+    .ok_or(format!("Port {} has tls enabled, but no tls_key", config.port))?;
+  let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())
+    .map_err(|err| format!("Failed to set up TLS: {:?}", err))?;
+  builder.set_private_key_file(key, SslFiletype::PEM)
+    .map_err(|err| format!("Failed to read TLS key {}: {:?}", key, err))?;
+  builder.set_certificate_chain_file(certificate)
+    .map_err(|err| format!("Failed to read TLS certificate {}: {:?}", certificate, err))?;
+  builder.check_private_key()
+    .map_err(|err| format!("TLS key {} does not match the certificate: {:?}", key, err))?;
+  Ok(TlsIdentity {
+    acceptor: Arc::new(builder.build()),
+    fingerprint: certificate_fingerprint(certificate),
+  })
+}
+
+/// The SHA-256 fingerprint of the certificate, in the lowercase hex format that xmrig accepts for
+/// its tls-fingerprint option.
+fn certificate_fingerprint(path: &str) -> Option<String> {
+  let mut pem = Vec::new();
+  File::open(path).and_then(|mut f| f.read_to_end(&mut pem)).ok()?;
+  let certificate = X509::from_pem(&pem).ok()?;
+  let digest = certificate.digest(MessageDigest::sha256()).ok()?;
+  Some(bytes_to_hex(digest.to_vec()))
+}
@@ -0,0 +1,261 @@
+//! Sessions written by hand in the format that xmrig, xmr-stak and xmr-node-proxy use, replayed
+//! against a stratum server that mines on a fixture block template.  They aren't recordings: the
+//! result hashes are placeholders that the pool never reads, and the port runs at difficulty 1, so
+//! any nonce meets the target.  What they check is that the miners keep understanding our
+//! responses, and that shares get turned down for the same reasons as on a real port.
+
+use jsonrpc_core::*;
+use jsonrpc_core::futures::sync::mpsc::{channel, Receiver};
+use jsonrpc_core::serde_json;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use toml;
+use app::App;
+use blocktemplate::*;
+use config::*;
+use db::DbAccess;
+use stratum::*;
+use stratum::codec::validate_request;
+
+/// A template blob from a private testnet, rebuilt with the 16 bytes of reserved space that we ask
+/// daemons for.
+const FIXTURE_BLOB: &str = "0100a5b6e1d205ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e2085948e8bb\
+  148e862b80000000001bfd53701ff83d53705e7aee92d0236238d7c671cd670c1e5d145aa38407aa7c4caf78c9c3\
+  a5086126c3d1e6d8bd48090dfc04a0288d398bf66e39e28888192a76534060cf698d293d3ed36ba25b23952ee868\
+  1a58080dd9da41702907aeacf368448e675dff25d15f74a2e55ca0155d09a6ee3ff22e9e8231e03e580a094a58d1\
+  d028cd8667114136db4b05fffa7359039243594749b3241cce28a782d2ace58cb1180c0caf384a302020a1e50d39\
+  fa6615e3b3a6ca883bd37a22f3870907bbc1dbbe70c1a6d6b4c1e343301926d835f688b901dea5d5e2c0df2251a2\
+  16d769b6cbabaa6fa81f3797aba88cc02100000000000000000000000000000000000";
+
+/// A template whose network difficulty is far above the difficulty of any share in the
+/// transcripts, so none of them turn into blocks.
+fn fixture_template() -> BlockTemplate {
+  serde_json::from_value(json!({
+    "blocktemplate_blob": FIXTURE_BLOB,
+    "difficulty": 1000000000000000u64,
+    "height": 911269,
+    "prev_hash": "ae9d4d429436d01430aaed0fd1a3823c46a14b5c993e2085948e8bb148e862b8",
+    "reserved_offset": 283,
+  })).unwrap()
+}
+
+/// A server for the first port in config.toml, at starting difficulty 1 so that every share from a
+/// miner that didn't ask for a difficulty is accepted.  Neither the daemon nor the database are
+/// ever reached.
+fn test_server(configure: &Fn(&mut Config)) -> (Arc<StratumServer>, MetaIoHandler<Meta>) {
+  let mut config: Config = toml::from_str(include_str!("../../config.toml")).unwrap();
+  config.daemon_url = Some("http://127.0.0.1:1/json_rpc".to_owned());
+  config.ports[0].starting_difficulty = 1;
+  configure(&mut config);
+  let server_config = config.ports[0].clone();
+  let app = Arc::new(App::with_db(config, DbAccess::unconnected()));
+  let job_provider = Arc::new(JobProvider::new(app.clone()));
+  assert!(job_provider.update_template(fixture_template()));
+  let server = Arc::new(StratumServer::new(app, &server_config, job_provider, None));
+  let io = build_io(&server);
+  (server, io)
+}
+
+/// A miner's connection, along with the jobs pushed to it and the IDs that the pool handed out.
+struct Session {
+  meta: Meta,
+  _jobs: Receiver<String>,
+  /// What the transcripts refer to as {address}, {miner_id}, {job_id}, {blob}, {target} and
+  /// {blocktemplate_blob}.  The IDs and the job are the ones from the latest login.
+  placeholders: HashMap<&'static str, String>,
+}
+
+impl Session {
+  fn connect(peer_addr: &str) -> Session {
+    let (sender, _jobs) = channel(8);
+    let address = include_str!("../../config.toml").lines()
+      .find(|line| line.starts_with("pool_wallet="))
+      .map(|line| line["pool_wallet=".len()..].trim_matches('"').to_owned())
+      .unwrap();
+    let mut placeholders = HashMap::new();
+    placeholders.insert("{address}", address);
+    Session {
+      meta: Meta {
+        peer_addr: Some(peer_addr.parse::<SocketAddr>().unwrap()),
+        sender: Some(sender),
+        closed: Some(Default::default()),
+        malformed_requests: Some(Default::default()),
+        timeout: None,
+      },
+      _jobs,
+      placeholders,
+    }
+  }
+
+  fn fill(&self, text: &str) -> String {
+    self.placeholders.iter()
+      .fold(text.to_owned(), |text, (name, value)| text.replace(name, value))
+  }
+
+  /// Sends the transcript's requests and compares the responses.
+  fn replay(&mut self, io: &MetaIoHandler<Meta>, transcript: &[(&str, &str)]) {
+    for &(request, expected_response) in transcript {
+      let request = self.fill(request);
+      assert!(validate_request(&request).is_ok(), "Rejected transcript request {}", request);
+      let response = io.handle_request_sync(&request, self.meta.clone()).unwrap();
+      let response: Value = serde_json::from_str(&response).unwrap();
+      // Logins hand out the IDs that the rest of the transcript refers to.
+      if let Some(result) = response.get("result") {
+        let placeholders = &mut self.placeholders;
+        let mut learn = |name, value: Option<&Value>| {
+          if let Some(value) = value.and_then(|value| value.as_str()) {
+            placeholders.insert(name, value.to_owned());
+          }
+        };
+        learn("{miner_id}", result.get("id"));
+        if let Some(job) = result.get("job") {
+          learn("{job_id}", job.get("job_id"));
+          learn("{blob}", job.get("blob"));
+          learn("{target}", job.get("target"));
+          learn("{blocktemplate_blob}", job.get("blocktemplate_blob"));
+        }
+      }
+      let expected_response: Value = serde_json::from_str(&self.fill(expected_response)).unwrap();
+      assert_eq!(response, expected_response, "Unexpected response to {}", request);
+    }
+  }
+}
+
+#[test]
+fn test_xmrig_transcript() {
+  let (_server, io) = test_server(&|_| {});
+  let mut session = Session::connect("198.51.100.7:41822");
+  session.replay(&io, &[
+    (r#"{"id":1,"jsonrpc":"2.0","method":"login","params":{"login":"{address}","pass":"x","agent":"XMRig/2.6.2 (Linux x86_64) libuv/1.8.0 gcc/5.4.0","algo":["cn","cn/0","cn/1","cn/xtl"]}}"#,
+     r#"{"id":1,"jsonrpc":"2.0","result":{"id":"{miner_id}","job":{"job_id":"{job_id}","blob":"{blob}","target":"ffffffff","algo":"cn/0"},"extensions":["algo","keepalive"],"status":"OK"}}"#),
+    (r#"{"id":4,"jsonrpc":"2.0","method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"1a000080","result":"5a3f9e4c1b2d7a6e8f0c3b5d9e1a2c4f6b8d0e2a4c6e8f1a3b5c7d9e0f2a0000","algo":"cn/0"}}"#,
+     r#"{"id":4,"jsonrpc":"2.0","result":{"status":"OK"}}"#),
+    (r#"{"id":5,"jsonrpc":"2.0","method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"1a000080","result":"5a3f9e4c1b2d7a6e8f0c3b5d9e1a2c4f6b8d0e2a4c6e8f1a3b5c7d9e0f2a0000","algo":"cn/0"}}"#,
+     r#"{"id":5,"jsonrpc":"2.0","error":{"code":-1,"message":"Duplicate share"}}"#),
+    (r#"{"id":6,"jsonrpc":"2.0","method":"submit","params":{"id":"{miner_id}","job_id":"b4a2c1e0-5d3f-4e6a-9b7c-8d1e2f3a4b5c","nonce":"1d000080","result":"5a3f9e4c1b2d7a6e8f0c3b5d9e1a2c4f6b8d0e2a4c6e8f1a3b5c7d9e0f2a0000","algo":"cn/0"}}"#,
+     r#"{"id":6,"jsonrpc":"2.0","error":{"code":-1,"message":"Block expired"}}"#),
+    (r#"{"id":7,"jsonrpc":"2.0","method":"keepalived","params":{"id":"{miner_id}"}}"#,
+     r#"{"id":7,"jsonrpc":"2.0","result":{"status":"KEEPALIVED"}}"#),
+  ]);
+}
+
+#[test]
+fn test_xmr_stak_transcript() {
+  let (_server, io) = test_server(&|_| {});
+  let mut session = Session::connect("203.0.113.24:50114");
+  // xmr-stak leaves out the jsonrpc version, and expects the same back.  This one asked for a
+  // difficulty that its share doesn't meet, which also gets its IP banned, so it goes last.
+  session.replay(&io, &[
+    (r#"{"method":"login","params":{"login":"{address}+1000000000000","pass":"x","rigid":"","agent":"xmr-stak/2.4.3/c5f0505/master/lin/cpu/monero/20"},"id":1}"#,
+     r#"{"id":1,"result":{"id":"{miner_id}","job":{"job_id":"{job_id}","blob":"{blob}","target":"00000000","algo":"cn/0"},"extensions":["algo","keepalive"],"status":"OK"}}"#),
+    (r#"{"method":"submit","params":{"id":"00000000-0000-0000-0000-000000000000","job_id":"{job_id}","nonce":"1e000080","result":"5a3f9e4c1b2d7a6e8f0c3b5d9e1a2c4f6b8d0e2a4c6e8f1a3b5c7d9e0f2a0000"},"id":1}"#,
+     r#"{"id":1,"error":{"code":-1,"message":"Unauthenticated"}}"#),
+    (r#"{"method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"1b000080","result":"5a3f9e4c1b2d7a6e8f0c3b5d9e1a2c4f6b8d0e2a4c6e8f1a3b5c7d9e0f2affff"},"id":1}"#,
+     r#"{"id":1,"error":{"code":-1,"message":"Low difficulty share"}}"#),
+  ]);
+  assert!(session.meta.is_closed());
+}
+
+#[test]
+fn test_nicehash_transcript() {
+  let (_server, io) = test_server(&|config| config.ports[0].nicehash = true);
+  let mut session = Session::connect("198.51.100.7:41822");
+  // NiceHash picks the top byte of the nonce for each of its miners, so two of them can find the
+  // same lower bytes.
+  session.replay(&io, &[
+    (r#"{"id":1,"jsonrpc":"2.0","method":"login","params":{"login":"{address}","pass":"x","agent":"XMRig/2.6.2 (Linux x86_64) libuv/1.8.0 gcc/5.4.0","algo":["cn","cn/0","cn/1","cn/xtl"]}}"#,
+     r#"{"id":1,"jsonrpc":"2.0","result":{"id":"{miner_id}","job":{"job_id":"{job_id}","blob":"{blob}","target":"ffffffff","algo":"cn/0","nicehash":true},"extensions":["algo","keepalive"],"status":"OK"}}"#),
+    (r#"{"id":4,"jsonrpc":"2.0","method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"1a00006b","result":"5a3f9e4c1b2d7a6e8f0c3b5d9e1a2c4f6b8d0e2a4c6e8f1a3b5c7d9e0f2a0000","algo":"cn/0"}}"#,
+     r#"{"id":4,"jsonrpc":"2.0","result":{"status":"OK"}}"#),
+    (r#"{"id":5,"jsonrpc":"2.0","method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"1a0000c4","result":"5a3f9e4c1b2d7a6e8f0c3b5d9e1a2c4f6b8d0e2a4c6e8f1a3b5c7d9e0f2a0000","algo":"cn/0"}}"#,
+     r#"{"id":5,"jsonrpc":"2.0","result":{"status":"OK"}}"#),
+  ]);
+  assert_eq!(&session.placeholders["{blob}"][78..86], "00000000");
+}
+
+#[test]
+fn test_xmr_node_proxy_transcript() {
+  let (_server, io) = test_server(&|_| {});
+  let mut session = Session::connect("192.0.2.51:38410");
+  // The proxy gets the whole template, with offsets into it for the nonces of its worker threads
+  // and their miners, and sends those nonces back with each share.
+  session.replay(&io, &[
+    (r#"{"method":"login","params":{"login":"{address}","pass":"x","agent":"xmr-node-proxy/0.0.3"},"id":1}"#,
+     r#"{"id":1,"result":{"id":"{miner_id}","job":{"id":"{miner_id}","job_id":"{job_id}","blocktemplate_blob":"{blocktemplate_blob}","difficulty":1000000000000000,"height":911269,"reserved_offset":282,"client_pool_offset":290,"client_nonce_offset":294,"target_diff":1,"target_diff_hex":"ffffffff","algo":"cn/0"},"extensions":["algo","keepalive"],"status":"OK"}}"#),
+    (r#"{"method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"2b010000","result":"8e4b3c2a9d1f7e6b5c4a3f2e1d0c9b8a7f6e5d4c3b2a1f0e9d8c7b6a5f4e0000","workerNonce":3,"poolNonce":1},"id":2}"#,
+     r#"{"id":2,"result":{"status":"OK"}}"#),
+    (r#"{"method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"2b010000","result":"8e4b3c2a9d1f7e6b5c4a3f2e1d0c9b8a7f6e5d4c3b2a1f0e9d8c7b6a5f4e0000","workerNonce":3,"poolNonce":1},"id":3}"#,
+     r#"{"id":3,"error":{"code":-1,"message":"Duplicate share"}}"#),
+    (r#"{"method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"2b010000","result":"0d9c8b7a6f5e4d3c2b1a0f9e8d7c6b5a4f3e2d1c0b9a8f7e6d5c4b3a2f1e0000","workerNonce":4,"poolNonce":1},"id":4}"#,
+     r#"{"id":4,"result":{"status":"OK"}}"#),
+    (r#"{"method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"2c010000","result":"8e4b3c2a9d1f7e6b5c4a3f2e1d0c9b8a7f6e5d4c3b2a1f0e9d8c7b6a5f4e0000"},"id":5}"#,
+     r#"{"id":5,"error":{"code":-32602,"message":"Proxies must submit a poolNonce and workerNonce"}}"#),
+  ]);
+  // The first job's extra nonce is 0, so the proxy gets the template just as the daemon sent it.
+  assert_eq!(session.placeholders["{blocktemplate_blob}"], FIXTURE_BLOB);
+}
+
+#[test]
+fn test_reconnect_transcript() {
+  let (server, io) = test_server(&|config| config.ports[0].max_connections_per_address = Some(1));
+  let mut session = Session::connect("198.51.100.7:41822");
+  session.replay(&io, &[
+    (r#"{"id":1,"jsonrpc":"2.0","method":"login","params":{"login":"{address}","pass":"x","agent":"XMRig/2.6.2 (Linux x86_64) libuv/1.8.0 gcc/5.4.0"}}"#,
+     r#"{"id":1,"jsonrpc":"2.0","result":{"id":"{miner_id}","job":{"job_id":"{job_id}","blob":"{blob}","target":"ffffffff","algo":"cn/0"},"extensions":["algo","keepalive"],"status":"OK"}}"#),
+    (r#"{"id":2,"jsonrpc":"2.0","method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"1a000080","result":"5a3f9e4c1b2d7a6e8f0c3b5d9e1a2c4f6b8d0e2a4c6e8f1a3b5c7d9e0f2a0000","algo":"cn/0"}}"#,
+     r#"{"id":2,"jsonrpc":"2.0","result":{"status":"OK"}}"#),
+  ]);
+
+  // The rig's connection drops, and it reconnects before the transport has noticed.
+  let mut reconnected = Session::connect("198.51.100.7:41830");
+  let login = r#"{"id":1,"jsonrpc":"2.0","method":"login","params":{"login":"{address}","pass":"x","agent":"XMRig/2.6.2 (Linux x86_64) libuv/1.8.0 gcc/5.4.0"}}"#;
+  reconnected.replay(&io, &[
+    (login,
+     r#"{"id":1,"jsonrpc":"2.0","error":{"code":-32602,"message":"Too many connections for this wallet address"}}"#),
+  ]);
+
+  // Once the transport reports the disconnect, the old session no longer counts against the limit,
+  // and the reconnect picks up where it left off.
+  session.meta.closed.as_ref().unwrap().store(true, Ordering::Relaxed);
+  reconnected.replay(&io, &[
+    (login,
+     r#"{"id":1,"jsonrpc":"2.0","result":{"id":"{miner_id}","job":{"job_id":"{job_id}","blob":"{blob}","target":"ffffffff","algo":"cn/0"},"extensions":["algo","keepalive"],"status":"OK"}}"#),
+  ]);
+  let miner = server.miner_connections.lock().unwrap()
+    .get(&reconnected.placeholders["{miner_id}"]).unwrap().clone();
+  assert_eq!(miner.trust.load(Ordering::Relaxed), 1);
+  assert_eq!(server.miner_connections.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn test_frozen_transcript() {
+  let (server, io) = test_server(&|config| {
+    config.max_template_age = Some(0);
+    config.freeze_jobs_when_stale = true;
+  });
+  let mut session = Session::connect("198.51.100.7:41822");
+  session.replay(&io, &[
+    (r#"{"id":1,"jsonrpc":"2.0","method":"login","params":{"login":"{address}","pass":"x","agent":"XMRig/2.6.2 (Linux x86_64) libuv/1.8.0 gcc/5.4.0"}}"#,
+     r#"{"id":1,"jsonrpc":"2.0","result":{"id":"{miner_id}","job":{"job_id":"{job_id}","blob":"{blob}","target":"ffffffff","algo":"cn/0"},"extensions":["algo","keepalive"],"status":"OK"}}"#),
+  ]);
+
+  // The template never gets a new block, so it goes stale at once, and the run loop freezes the
+  // pool.  Shares for the jobs that miners already had no longer count.
+  assert_eq!(server.job_provider.check_staleness(), Staleness::Stale);
+  server.drop_all_jobs();
+  session.replay(&io, &[
+    (r#"{"id":2,"jsonrpc":"2.0","method":"submit","params":{"id":"{miner_id}","job_id":"{job_id}","nonce":"1a000080","result":"5a3f9e4c1b2d7a6e8f0c3b5d9e1a2c4f6b8d0e2a4c6e8f1a3b5c7d9e0f2a0000","algo":"cn/0"}}"#,
+     r#"{"id":2,"jsonrpc":"2.0","error":{"code":-1,"message":"Block expired"}}"#),
+  ]);
+
+  // Miners can still log in, but only get a job once the daemon recovers.
+  let mut session = Session::connect("203.0.113.24:50114");
+  session.replay(&io, &[
+    (r#"{"id":1,"jsonrpc":"2.0","method":"login","params":{"login":"{address}","pass":"x","agent":"XMRig/2.6.2 (Linux x86_64) libuv/1.8.0 gcc/5.4.0"}}"#,
+     r#"{"id":1,"jsonrpc":"2.0","result":{"id":"{miner_id}","extensions":["algo","keepalive"],"status":"OK"}}"#),
+    (r#"{"id":2,"jsonrpc":"2.0","method":"getjob","params":{"id":"{miner_id}"}}"#,
+     r#"{"id":2,"jsonrpc":"2.0","error":{"code":-1,"message":"Pool is waiting for its daemon to catch up with the network"}}"#),
+  ]);
+}
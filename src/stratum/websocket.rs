@@ -0,0 +1,124 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use jsonrpc_core::MetaIoHandler;
+use jsonrpc_core::futures::Stream;
+use jsonrpc_core::futures::sync::mpsc::{channel, Sender};
+use ws;
+use ws::{CloseCode, Handler, Handshake, Message};
+use ws::util::Token;
+use stratum::Meta;
+use stratum::codec::*;
+use stratum::timeouts::{ConnectionTimeout, Timeouts};
+
+/// How often we check whether the pool wants the connection closed, or it has timed out.
+const CLOSE_CHECK_INTERVAL_MS: u64 = 1000;
+const CLOSE_CHECK: Token = Token(1);
+
+/// A browser miner's connection.  Each text frame holds one JSON-RPC request, and gets answered
+/// with one frame holding the response, so the stratum methods are the same as on the TCP ports.
+struct WebSocketSession {
+  io: Arc<MetaIoHandler<Meta>>,
+  out: ws::Sender,
+  peer_addr: Option<SocketAddr>,
+  sender: Sender<String>,
+  closed: Arc<AtomicBool>,
+  malformed_requests: Arc<AtomicUsize>,
+  timeout: Arc<ConnectionTimeout>,
+}
+
+impl Handler for WebSocketSession {
+  fn on_open(&mut self, shake: Handshake) -> ws::Result<()> {
+    self.peer_addr = shake.peer_addr;
+    self.out.timeout(CLOSE_CHECK_INTERVAL_MS, CLOSE_CHECK)
+  }
+
+  fn on_close(&mut self, _code: CloseCode, _reason: &str) {
+    self.closed.store(true, Ordering::Relaxed);
+  }
+
+  fn on_timeout(&mut self, event: Token) -> ws::Result<()> {
+    if event != CLOSE_CHECK {
+      return Ok(());
+    }
+    if self.closed.load(Ordering::Relaxed) {
+      self.out.close(CloseCode::Away)
+    } else if self.timeout.expired() {
+      debug!("Closing websocket connection from {:?}, which timed out", self.peer_addr);
+      self.out.close(CloseCode::Away)
+    } else {
+      self.out.timeout(CLOSE_CHECK_INTERVAL_MS, CLOSE_CHECK)
+    }
+  }
+
+  fn on_message(&mut self, message: Message) -> ws::Result<()> {
+    if let Message::Text(request) = message {
+      if request.len() > MAX_LINE_LENGTH {
+        info!("Dropping websocket connection from {:?}: message too long", self.peer_addr);
+        return self.out.close(CloseCode::Size);
+      }
+      if let Err(reason) = validate_request(&request) {
+        info!("Malformed request from {:?}: {}", self.peer_addr, reason);
+        self.malformed_requests.fetch_add(1, Ordering::Relaxed);
+      }
+      let meta = Meta {
+        peer_addr: self.peer_addr,
+        sender: Some(self.sender.clone()),
+        closed: Some(self.closed.clone()),
+        malformed_requests: Some(self.malformed_requests.clone()),
+        timeout: Some(self.timeout.clone()),
+      };
+      if let Some(response) = self.io.handle_request_sync(&request, meta) {
+        self.out.send(response)?;
+      }
+      if self.malformed_requests.load(Ordering::Relaxed) >= MAX_MALFORMED_REQUESTS {
+        warn!("Dropping websocket connection from {:?} after too many malformed requests",
+              self.peer_addr);
+        return self.out.close(CloseCode::Policy);
+      }
+    }
+    Ok(())
+  }
+}
+
+pub fn serve(io: Arc<MetaIoHandler<Meta>>, address: &SocketAddr, timeouts: Timeouts) {
+  let address = address.to_owned();
+  thread::spawn(move || {
+    // ws buffers whole frames, and a message can be split over several of them, so both have to be
+    // bounded for a long request to be refused before it takes up memory.
+    let settings = ws::Settings {
+      max_fragment_size: MAX_LINE_LENGTH,
+      fragments_grow: false,
+      ..ws::Settings::default()
+    };
+    let server = ws::Builder::new().with_settings(settings).build(|out: ws::Sender| {
+      // Jobs are pushed through the same kind of channel that the TCP ports use, so we forward
+      // anything sent on it to the websocket.
+      let (sender, receiver) = channel::<String>(64);
+      let job_out = out.clone();
+      thread::spawn(move || {
+        for message in receiver.wait() {
+          match message {
+            Ok(message) => if job_out.send(message).is_err() {
+              break;
+            },
+            Err(_) => break,
+          }
+        }
+      });
+      WebSocketSession {
+        io: io.clone(),
+        out,
+        peer_addr: None,
+        sender,
+        closed: Arc::new(AtomicBool::new(false)),
+        malformed_requests: Arc::new(AtomicUsize::new(0)),
+        timeout: timeouts.start(),
+      }
+    });
+    if let Err(err) = server.and_then(|server| server.listen(address)) {
+      error!("Websocket server on {} stopped: {:?}", address, err);
+    }
+  });
+}
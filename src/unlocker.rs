@@ -153,16 +153,26 @@ mod tests {
   fn test_fee_percentages() {
     let fee_config = Config {
       hash_type: String::new(),
+      forks: Vec::new(),
       log_level: String::new(),
       log_file: String::new(),
-      daemon_url: String::new(),
+      daemon_url: None,
+      daemons: Vec::new(),
       wallet_url: String::new(),
+      zmq_url: None,
       payment_mixin: 0,
       network_transaction_fee: 0,
+      template_refresh_interval: None,
+      min_fee_delta: None,
+      block_target_time: None,
+      max_template_age: None,
+      freeze_jobs_when_stale: false,
       min_payment: 0.0,
       payment_denomination: 0.0,
       pool_wallet: "pool".to_owned(),
       pool_fee: 10.0,
+      admin_token: None,
+      api: Default::default(),
       donations: vec![Donation {
         address: "dev".to_owned(),
         percentage: 15.0,
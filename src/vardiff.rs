@@ -0,0 +1,210 @@
+use std::cmp::{min, max};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use config::ServerConfig;
+
+/// Where the vardiff engine gets the current time from, so that tests can control it.
+pub trait Clock {
+  fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+}
+
+/// The number of shares needed in the window before we trust the hashrate estimate, unless the
+/// miner has been connected for the whole window.
+const MIN_WINDOW_SHARES: usize = 4;
+/// When the ideal difficulty is this many times higher or lower than the current one, the miner
+/// gets a new job right away instead of waiting for the next template.
+const IMMEDIATE_RETARGET_RATIO: f64 = 2.0;
+
+pub struct VarDiffSettings {
+  pub target_time: u64,
+  pub window: Duration,
+  pub variance_tolerance: f64,
+  pub min_difficulty: u64,
+  pub max_difficulty: u64,
+}
+
+impl<'a> From<&'a ServerConfig> for VarDiffSettings {
+  fn from(config: &ServerConfig) -> VarDiffSettings {
+    VarDiffSettings {
+      target_time: config.target_time,
+      window: Duration::from_secs(config.vardiff_window.unwrap_or(120)),
+      variance_tolerance: config.variance_tolerance.unwrap_or(0.25),
+      min_difficulty: config.min_difficulty.unwrap_or(1),
+      max_difficulty: config.max_difficulty.unwrap_or(u64::max_value()),
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Retarget {
+  Unchanged,
+  /// The miner's next job should use this difficulty.
+  NextJob(u64),
+  /// The difficulty is far enough off that the miner should get a job with this difficulty now.
+  Immediate(u64),
+}
+
+/// Estimates a miner's hashrate from the shares it submitted within a rolling window, so that the
+/// difficulty follows changes in hashrate within a couple of minutes.
+pub struct VarDiff<C: Clock = SystemClock> {
+  clock: C,
+  session_start: Instant,
+  shares: VecDeque<(Instant, u64)>,
+}
+
+impl VarDiff<SystemClock> {
+  pub fn new() -> VarDiff<SystemClock> {
+    VarDiff::with_clock(SystemClock)
+  }
+}
+
+impl<C: Clock> VarDiff<C> {
+  pub fn with_clock(clock: C) -> VarDiff<C> {
+    let session_start = clock.now();
+    VarDiff {
+      clock,
+      session_start,
+      shares: VecDeque::new(),
+    }
+  }
+
+  /// Records a share of the given difficulty, and works out whether the current difficulty should
+  /// change.
+  pub fn record_share(&mut self, shares: u64, current_difficulty: u64, settings: &VarDiffSettings)
+                      -> Retarget {
+    let now = self.clock.now();
+    self.shares.push_back((now, shares));
+    while self.shares.front().map_or(false, |&(time, _)| now.duration_since(time) > settings.window) {
+      self.shares.pop_front();
+    }
+    let connected_for = now.duration_since(self.session_start);
+    if self.shares.len() < MIN_WINDOW_SHARES && connected_for < settings.window {
+      return Retarget::Unchanged;
+    }
+    let elapsed = min(connected_for, settings.window);
+    let elapsed_secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    if elapsed_secs <= 0.0 {
+      return Retarget::Unchanged;
+    }
+    let window_shares: u64 = self.shares.iter().map(|&(_, shares)| shares).sum();
+    let hashrate = window_shares as f64 / elapsed_secs;
+    let ideal_difficulty = (hashrate * settings.target_time as f64).round() as u64;
+    let ideal_difficulty = min(max(ideal_difficulty, settings.min_difficulty), settings.max_difficulty);
+    let difficulty_ratio = ideal_difficulty as f64 / current_difficulty as f64;
+    if (difficulty_ratio - 1.0).abs() <= settings.variance_tolerance {
+      Retarget::Unchanged
+    } else if difficulty_ratio >= IMMEDIATE_RETARGET_RATIO
+      || difficulty_ratio <= 1.0 / IMMEDIATE_RETARGET_RATIO {
+      Retarget::Immediate(ideal_difficulty)
+    } else {
+      Retarget::NextJob(ideal_difficulty)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use vardiff::*;
+  use std::cell::Cell;
+
+  struct MockClock {
+    now: Cell<Instant>,
+  }
+
+  impl MockClock {
+    fn advance(&self, secs: u64) {
+      self.now.set(self.now.get() + Duration::from_secs(secs));
+    }
+  }
+
+  impl Clock for MockClock {
+    fn now(&self) -> Instant {
+      self.now.get()
+    }
+  }
+
+  fn test_settings() -> VarDiffSettings {
+    VarDiffSettings {
+      target_time: 10,
+      window: Duration::from_secs(60),
+      variance_tolerance: 0.25,
+      min_difficulty: 100,
+      max_difficulty: 100000,
+    }
+  }
+
+  fn test_vardiff() -> VarDiff<MockClock> {
+    VarDiff::with_clock(MockClock {
+      now: Cell::new(Instant::now()),
+    })
+  }
+
+  #[test]
+  fn test_steady_miner_keeps_difficulty() {
+    let mut vardiff = test_vardiff();
+    let settings = test_settings();
+    for _ in 0..20 {
+      vardiff.clock.advance(10);
+      assert_eq!(vardiff.record_share(1000, 1000, &settings), Retarget::Unchanged);
+    }
+  }
+
+  #[test]
+  fn test_waits_for_enough_shares() {
+    let mut vardiff = test_vardiff();
+    let settings = test_settings();
+    for _ in 0..(MIN_WINDOW_SHARES - 1) {
+      vardiff.clock.advance(1);
+      assert_eq!(vardiff.record_share(1000, 1000, &settings), Retarget::Unchanged);
+    }
+    vardiff.clock.advance(1);
+    // 4000 shares in 4 seconds with a 10 second target time.
+    assert_eq!(vardiff.record_share(1000, 1000, &settings), Retarget::Immediate(10000));
+  }
+
+  #[test]
+  fn test_small_change_waits_for_next_job() {
+    let mut vardiff = test_vardiff();
+    let settings = test_settings();
+    let mut result = Retarget::Unchanged;
+    for _ in 0..10 {
+      vardiff.clock.advance(6);
+      result = vardiff.record_share(1000, 1000, &settings);
+    }
+    // A share every 6 seconds, with a 10 second target time.
+    assert_eq!(result, Retarget::NextJob(1667));
+  }
+
+  #[test]
+  fn test_window_follows_hashrate_drop() {
+    let mut vardiff = test_vardiff();
+    let settings = test_settings();
+    for _ in 0..12 {
+      vardiff.clock.advance(5);
+      vardiff.record_share(1000, 2000, &settings);
+    }
+    // The rig loses most of its hashrate, and the earlier fast shares fall out of the window.
+    vardiff.clock.advance(40);
+    vardiff.record_share(1000, 2000, &settings);
+    vardiff.clock.advance(40);
+    assert_eq!(vardiff.record_share(1000, 2000, &settings), Retarget::Immediate(333));
+  }
+
+  #[test]
+  fn test_difficulty_limits() {
+    let mut vardiff = test_vardiff();
+    let settings = test_settings();
+    vardiff.clock.advance(60);
+    assert_eq!(vardiff.record_share(1, 1000, &settings), Retarget::Immediate(100));
+    vardiff.clock.advance(1);
+    assert_eq!(vardiff.record_share(1000000000, 1000, &settings), Retarget::Immediate(100000));
+  }
+}